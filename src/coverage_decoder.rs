@@ -1,44 +1,180 @@
 use crate::cpu::PtCpu;
 use crate::image::PtImage;
+#[cfg(feature = "cbr")]
+use crate::packet::cbr::Cbr;
+#[cfg(feature = "event")]
+use crate::packet::cfe::Cfe;
 use crate::packet::decoder::PtPacketDecoder;
 use crate::packet::mode::{AddressingMode, ModeExec, ModeTsx, TransactionState};
 use crate::packet::pip::Pip;
 use crate::packet::tip::{Fup, Tip, TipPgd, TipPge};
 use crate::packet::tnt::TntIter;
+use crate::packet::trig::Trig;
 use crate::packet::vmcs::Vmcs;
 use crate::packet::{PtPacket, PtPacketParseError};
-use crate::utils::fmix64;
+use crate::utils::{fmix64, is_canonical_48};
 use iced_x86::{Code, FlowControl, Instruction, Register};
-use num_traits::SaturatingAdd;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::fmt::Debug;
+use std::mem;
+use std::slice;
+use std::sync::atomic::{AtomicU8, AtomicU16, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
 
-pub trait CoverageEntry: Copy + Debug + From<u8> + SaturatingAdd {}
-impl<T> CoverageEntry for T where T: Copy + Debug + From<u8> + SaturatingAdd {}
+/// Direct branches a code walk follows before failing with [`PtDecoderError::InfiniteLoop`]
+const MAX_DIRECT_BRANCHES: usize = 1 << 16;
 
-#[derive(Debug, PartialEq)]
+/// Element of a coverage map
+pub trait CoverageCell: Debug {
+    /// Record a hit of the edge mapped to this cell
+    fn increment(&mut self);
+
+    /// Returns true if the cell recorded at least one hit
+    fn is_hit(&self) -> bool;
+}
+
+pub trait CoverageEntry: CoverageCell {}
+impl<T> CoverageEntry for T where T: CoverageCell {}
+
+macro_rules! impl_coverage_cell_int {
+    ($($ty:ty),+ $(,)?) => {
+        $(
+            impl CoverageCell for $ty {
+                fn increment(&mut self) {
+                    *self = self.saturating_add(1);
+                }
+
+                fn is_hit(&self) -> bool {
+                    *self != 0
+                }
+            }
+        )+
+    };
+}
+impl_coverage_cell_int!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize
+);
+
+// Incrementing through `&mut` does not need atomic operations, the map is borrowed exclusively
+// while decoding.
+macro_rules! impl_coverage_cell_atomic {
+    ($($ty:ty),+ $(,)?) => {
+        $(
+            impl CoverageCell for $ty {
+                fn increment(&mut self) {
+                    let value = self.get_mut();
+                    *value = value.saturating_add(1);
+                }
+
+                fn is_hit(&self) -> bool {
+                    self.load(Ordering::Relaxed) != 0
+                }
+            }
+        )+
+    };
+}
+impl_coverage_cell_atomic!(AtomicU8, AtomicU16, AtomicU32, AtomicU64, AtomicUsize);
+
+/// Edge-hit set: a cell is set when the edge is hit at least once
+impl CoverageCell for bool {
+    fn increment(&mut self) {
+        *self = true;
+    }
+
+    fn is_hit(&self) -> bool {
+        *self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 #[non_exhaustive]
 pub enum PtDecoderError {
+    /// A TNT bit compresses a return while the return compression stack is empty, e.g. because
+    /// the stack is not as deep as the calls
+    EmptyReturnStack,
     Eof,
+    /// A TIP.PGE targets an IP outside the IP filter ranges, or a TIP.PGD an IP inside them, see
+    /// [`PtCoverageDecoderBuilder::check_filter_en_ranges`]
+    FilterEnRangesMismatch(u64),
+    ImageTooLarge,
+    /// The trace requires walking the code while tracing is disabled, e.g. a TNT or TIP not
+    /// preceded by a TIP.PGE or a PSB+ FUP. The trace is inconsistent with itself, the images are
+    /// not involved.
     IncoherentState,
+    /// The code walked in the images does not match the trace, e.g. a TIP where the walk reaches
+    /// a conditional branch, or a TIP.PGD where it reaches a MOV CR3.
     IncoherentImage,
+    /// The code walk followed too many direct branches without reaching an instruction that
+    /// needs the trace, e.g. at a `jmp $`. Carries the IP where the walk stopped.
+    InfiniteLoop(u64),
+    /// The check set by [`PtCoverageDecoderBuilder::integrity_check`] rejected a segment of the
+    /// trace
+    IntegrityCheckFailed,
     InvalidArgument,
-    InvalidPacketSequence { packets: Vec<PtPacket> },
+    InvalidPacketSequence {
+        packets: Vec<PtPacket>,
+    },
+    IpSuppressed,
     MalformedInstruction,
     MalformedPacket,
-    MalformedPsbPlus,
-    MissingImage { address: u64 },
+    MalformedPsbPlus {
+        packet: PtPacket,
+    },
+    MissingImage {
+        address: u64,
+    },
+    NonCanonicalIp(u64),
+    /// The IP set by a PSB+ FUP is not in any image, see
+    /// [`PtCoverageDecoderBuilder::check_psbplus_ip`]
+    PsbPlusIpOutsideImages(u64),
     SyncFailed,
+    /// The trace ends in the middle of a packet, e.g. a capture cut at an arbitrary byte. A trace
+    /// ending at a packet boundary is decoded successfully.
+    TruncatedTrace,
+    UnknownPacket {
+        b0: u8,
+        b1: Option<u8>,
+    },
     // todo: if an OVF packet is encountered, the coverage might be incomplete and a source of
     // fuzzer instability. Consider returning this information so that a fuzzer using this lib can
     // decide to trash the execution and repeat it.
 }
 
+impl PtDecoderError {
+    /// Likely cause of the error and how to address it, for the errors that are usually caused
+    /// by the decoder configuration rather than by a corrupted trace.
+    pub const fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::IncoherentState => Some(
+                "the trace walks code while tracing is disabled; check that it starts at a PSB \
+                 or use PtCoverageDecoderBuilder::assume_enabled_at",
+            ),
+            Self::IncoherentImage => Some(
+                "the trace jumped to an address whose bytes don't match the provided image; \
+                 check the image load addresses (ASLR slide) and that the images match the traced \
+                 binaries",
+            ),
+            Self::MissingImage { .. } => Some(
+                "the traced code is not in any image; add the missing image or use \
+                 PtCoverageDecoderBuilder::skip_missing_images",
+            ),
+            Self::SyncFailed => {
+                Some("no PSB packet in the trace; check that it is an Intel PT trace")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Packet level errors keep their meaning at the decoder level, so that they can be propagated
+/// with `?`.
 impl From<PtPacketParseError> for PtDecoderError {
     fn from(value: PtPacketParseError) -> Self {
         match value {
             PtPacketParseError::MalformedPacket => Self::MalformedPacket,
             PtPacketParseError::Eof => Self::Eof,
+            PtPacketParseError::Truncated => Self::TruncatedTrace,
+            PtPacketParseError::UnknownPacket { b0, b1 } => Self::UnknownPacket { b0, b1 },
         }
     }
 }
@@ -48,113 +184,437 @@ pub struct PtCoverageDecoderBuilder {
     cpu: Option<PtCpu>, // todo: consider if caching the errata makes sense
     images: Vec<PtImage>,
     filter_vmx_non_root: bool,
+    check_canonical_ip: bool,
+    check_psbplus_ip: bool,
+    check_filter_en_ranges: bool,
+    userspace_only: bool,
+    kernel_address_start: u64,
+    ring_filter: Option<Ring>,
+    skip_unknown_mode: bool,
+    tolerate_missing_psbend: bool,
+    exclude_aborted_transactions: bool,
+    edge_image_indices: bool,
+    skip_missing_images: bool,
+    cr3_images: Vec<(u64, Vec<PtImage>)>,
+    excluded_cr3s: Vec<u64>,
+    masked_ranges: Vec<(u64, u64)>,
+    filter_en_ranges: Vec<(u64, u64)>,
+    relative_coverage: bool,
+    decoder_options: u32,
+    initial_addressing_mode: AddressingMode,
+    assume_enabled_at: Option<u64>,
+    skip_initial_psb: bool,
+    decode_until_offset: Option<usize>,
+    instruction_hook: Option<Callback<InstructionHookFn>>,
+    trigger_sink: Option<Callback<TriggerSinkFn>>,
+    integrity_check: Option<Callback<IntegrityCheckFn>>,
+    #[cfg(feature = "retc")]
+    ret_comp_stack_depth: usize,
 }
 
-#[derive(Debug)]
+/// Callback set on a [`PtCoverageDecoderBuilder`], shared by its clones and compared by identity
+struct Callback<F: ?Sized>(Arc<F>);
+
+impl<F: ?Sized> Clone for Callback<F> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<F: ?Sized> PartialEq for Callback<F> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl<F: ?Sized> Debug for Callback<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+type InstructionHookFn = Mutex<dyn FnMut(&Instruction) + Send>;
+
+type TriggerSinkFn = Mutex<dyn FnMut(&Trigger) + Send>;
+
+type IntegrityCheckFn = Mutex<dyn FnMut(&[u8]) -> bool + Send>;
+
 pub struct PtCoverageDecoder {
     builder: PtCoverageDecoderBuilder,
 
     is_syncd: bool,
     state: ExecutionState,
-    proceed_inst_cache: HashMap<u64, (u64, ProceedInstStopReason)>, // todo cr3 + vmcs should be in the key as well
+    /// Keyed by IP and CR3
+    proceed_inst_cache: HashMap<(u64, Option<u64>), (u64, ProceedInstStopReason)>, // todo vmcs should be in the key as well
+    #[cfg(feature = "cache")]
+    inst_cache: HashMap<(u64, Option<u64>, AddressingMode), DecodedInstruction>, // todo vmcs should be in the key as well
+    #[cfg(feature = "log_coverage")]
+    coverage_entry_edges: HashMap<(Option<usize>, usize), (u64, u64)>,
+    /// Edges recorded in the current TSX transaction and the map they belong to, see
+    /// [`PtCoverageDecoderBuilder::exclude_aborted_transactions`]
+    transaction_coverage: Vec<(Option<usize>, Edge)>,
+    /// See [`PtCoverageDecoder::coverage_with_instruction_ips`]
+    instruction_ips: Option<Vec<u64>>,
+    /// See [`PtCoverageDecoder::progress`]
+    trace_position: usize,
+    trace_len: usize,
+    #[cfg(test)]
+    inst_decoder_builds: usize,
+}
+
+/// Summary of the decoding state, for bug reports. The images and the caches are not printed.
+impl Debug for PtCoverageDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = &self.state;
+        f.debug_struct("PtCoverageDecoder")
+            .field("is_syncd", &self.is_syncd)
+            .field("packet_en", &state.packet_en)
+            .field("ip", &format_args!("{:#x}", state.ip))
+            .field("tip_last_ip", &format_args!("{:#x}", state.tip_last_ip))
+            .field("cr3", &state.cr3)
+            .field("non_root_vmx", &state.pip.non_root_vmx())
+            .field("vmcs", &self.current_vmcs())
+            .field("addressing_mode", &state.mode_exec.addressing_mode())
+            .field("transaction_state", &state.mode_tsx.transaction_state())
+            .field("ring", &state.ring)
+            .field("save_coverage", &state.save_coverage)
+            .field("flow_start_position", &state.flow_start_position)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Buffers of a [`PtCoverageDecoder`] that can be reused by the next one, to avoid reallocating
+/// them when decoding many small traces.
+///
+/// See [`PtCoverageDecoderBuilder::build_with_scratch`] and [`PtCoverageDecoder::into_scratch`].
+#[derive(Debug, Default)]
+pub struct DecoderScratch {
+    proceed_inst_cache: HashMap<(u64, Option<u64>), (u64, ProceedInstStopReason)>,
+    #[cfg(feature = "cache")]
+    inst_cache: HashMap<(u64, Option<u64>, AddressingMode), DecodedInstruction>,
+    #[cfg(feature = "log_coverage")]
+    coverage_entry_edges: HashMap<(Option<usize>, usize), (u64, u64)>,
+    transaction_coverage: Vec<(Option<usize>, Edge)>,
+    #[cfg(feature = "retc")]
+    ret_comp_stack: VecDeque<u64>,
+}
+
+impl DecoderScratch {
+    pub fn new() -> Self {
+        Self::default()
+    }
 }
 
 #[derive(Debug)]
 struct ExecutionState {
     packet_en: bool,
     pip: Pip,
+    /// CR3 of the last PIP, None until the first one
+    cr3: Option<u64>,
     tip_last_ip: u64,
     ip: u64,
     vmcs: Option<Vmcs>,
     mode_exec: ModeExec,
+    /// A MODE.Exec was decoded, otherwise `mode_exec` is the initial addressing mode
+    mode_exec_seen: bool,
     mode_tsx: ModeTsx,
     save_coverage: bool,
+    /// A VMLAUNCH/VMRESUME was reached, its guest target is carried by the next TIP
+    vm_entry_pending: bool,
+    /// Current ring, None until the first ring transition
+    ring: Option<Ring>,
+    /// Core:bus ratio of the last CBR, None until the first one
+    #[cfg(feature = "cbr")]
+    cbr: Option<u8>,
+    /// Value of the last TSC, the timing anchor, None until the first one
+    #[cfg(feature = "tsc")]
+    tsc: Option<u64>,
+    /// Trace position following the first PSB+, None until the first PSB+ is decoded
+    flow_start_position: Option<usize>,
     #[cfg(feature = "retc")]
-    ret_comp_stack: Vec<u64>,
+    ret_comp_stack: VecDeque<u64>,
 }
 
 #[derive(Debug)]
-struct CovDecIterationState<'a, CE: Debug> {
-    packet_decoder: PtPacketDecoder<'a>,
-    coverage: &'a mut [CE],
+struct CovDecIterationState<'a, 't, 'm, CE: Debug> {
+    packet_decoder: PtPacketDecoder<'t>,
+    /// None when decoding without recording coverage
+    coverage: Option<&'a mut [CE]>,
+    /// One coverage map per image, replaces `coverage` when set
+    image_coverages: Option<&'a mut [&'m mut [CE]]>,
+    /// Coverage map indexes hit for the first time, in hit order
+    new_entries: Option<&'a mut Vec<usize>>,
+    /// Recorded edges, in hit order
+    edges: Option<&'a mut Vec<Edge>>,
+    /// Decoding events, in trace order
+    events: Option<&'a mut Vec<PtDecoderEvent>>,
+    /// Recorded indirect and far call edges, in hit order
+    indirect_calls: Option<&'a mut Vec<(u64, u64)>>,
+    /// Position of the packet decoder past which decoding stops, see
+    /// [`PtCoverageDecoderBuilder::decode_until_offset`]
+    end: usize,
 }
 
-impl<'a, CE> CovDecIterationState<'a, CE>
+impl<'a, 't, CE> CovDecIterationState<'a, 't, '_, CE>
 where
     CE: CoverageEntry,
 {
     fn new(
         cov_dec: &mut PtCoverageDecoder,
-        pt_trace: &'a [u8],
-        coverage: &'a mut [CE],
+        pt_trace: &'t [u8],
+        coverage: Option<&'a mut [CE]>,
     ) -> Result<Self, PtDecoderError> {
-        if coverage.is_empty() {
-            return Err(PtDecoderError::InvalidArgument);
-        }
-
         let packet_decoder = if cov_dec.is_syncd {
             PtPacketDecoder::new_not_syncd(pt_trace)
         } else {
             let pd = PtPacketDecoder::new(pt_trace)?;
             cov_dec.is_syncd = true;
             pd
-        };
+        }
+        .skip_unknown_mode(cov_dec.builder.skip_unknown_mode);
 
         Ok(Self {
             packet_decoder,
             coverage,
+            image_coverages: None,
+            new_entries: None,
+            edges: None,
+            events: None,
+            indirect_calls: None,
+            end: cov_dec.builder.decode_until_offset.unwrap_or(usize::MAX),
         })
     }
+
+    fn event(&mut self, event: PtDecoderEvent) {
+        if let Some(events) = &mut self.events {
+            events.push(event);
+        }
+    }
+
+    /// The map of the image with index `map`, or the main one if None
+    fn map(&mut self, map: Option<usize>) -> Option<&mut [CE]> {
+        match (map, &mut self.image_coverages) {
+            (Some(index), Some(maps)) => Some(maps[index]),
+            _ => self.coverage.as_deref_mut(),
+        }
+    }
+
+    /// Record `edge`, incrementing the `cov_entry` cell of the `map` coverage map if any
+    fn hit(&mut self, map: Option<usize>, cov_entry: Option<usize>, edge: Edge) {
+        if let Some(edges) = &mut self.edges {
+            edges.push(edge);
+        }
+        if let Some(indirect_calls) = &mut self.indirect_calls
+            && matches!(
+                edge.class,
+                InstructionClass::CallIndirect | InstructionClass::FarCall
+            )
+        {
+            indirect_calls.push((edge.from, edge.to));
+        }
+
+        let (Some(cov_entry), Some(map)) = (cov_entry, self.map(map)) else {
+            return;
+        };
+        let cell = &mut map[cov_entry];
+        let first_hit = !cell.is_hit();
+        cell.increment();
+
+        if first_hit && let Some(new_entries) = &mut self.new_entries {
+            new_entries.push(cov_entry);
+        }
+    }
+}
+
+/// The subset of a decoded instruction needed to walk the code
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DecodedInstruction {
+    class: InstructionClass,
+    next_ip: u64,
+    branch_target: u64,
+    to_ring: Option<Ring>,
+}
+
+impl From<&Instruction> for DecodedInstruction {
+    fn from(instruction: &Instruction) -> Self {
+        Self {
+            class: InstructionClass::from(instruction),
+            next_ip: instruction.next_ip(),
+            branch_target: instruction.near_branch_target(),
+            to_ring: Ring::after(instruction),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum ProceedInstStopReason {
-    CondBranch { to: u64 },
-    FarIndirect,
-    Indirect,
+    CondBranch {
+        to: u64,
+    },
+    FarIndirect {
+        to_ring: Option<Ring>,
+        class: InstructionClass,
+    },
+    Indirect {
+        class: InstructionClass,
+    },
     MovCr3,
+    /// The walk left the ranges set by [`PtCoverageDecoderBuilder::filter_en_ranges`]
+    OutsideFilterEnRanges,
     Return,
     UntilIpReached,
+    VmEntry,
+}
+
+impl ProceedInstStopReason {
+    /// Class of the instruction the walk stopped at
+    const fn class(self) -> InstructionClass {
+        match self {
+            Self::CondBranch { .. } => InstructionClass::CondBranch,
+            Self::FarIndirect { class, .. } | Self::Indirect { class } => class,
+            Self::MovCr3 => InstructionClass::MovCr3,
+            Self::Return => InstructionClass::Return,
+            Self::OutsideFilterEnRanges | Self::UntilIpReached => InstructionClass::Other,
+            Self::VmEntry => InstructionClass::VmEntry,
+        }
+    }
 }
 
 impl ExecutionState {
-    const fn new() -> Self {
+    const fn new(addressing_mode: AddressingMode) -> Self {
         Self {
             packet_en: false,
             pip: Pip { raw: [0; 6] },
+            cr3: None,
             tip_last_ip: 0, // SDM 34.4.2.2 “Last IP” is initialized to zero
             ip: 0,
             vmcs: None,
-            mode_exec: ModeExec::new(AddressingMode::_16, false),
+            mode_exec: ModeExec::new(addressing_mode, false),
+            mode_exec_seen: false,
             mode_tsx: ModeTsx::new(TransactionState::Commit),
             save_coverage: true,
+            vm_entry_pending: false,
+            ring: None,
+            #[cfg(feature = "cbr")]
+            cbr: None,
+            #[cfg(feature = "tsc")]
+            tsc: None,
+            flow_start_position: None,
             #[cfg(feature = "retc")]
-            ret_comp_stack: Vec::new(), // const hack, Vec::with_capacity(64) should be a better fit
+            ret_comp_stack: VecDeque::new(), // const hack, VecDeque::with_capacity(64) should be a better fit
+        }
+    }
+
+    /// State before the first packet, see [`PtCoverageDecoderBuilder::assume_enabled_at`]
+    const fn initial(builder: &PtCoverageDecoderBuilder) -> Self {
+        let mut state = Self::new(builder.initial_addressing_mode);
+        if let Some(ip) = builder.assume_enabled_at {
+            state.packet_en = true;
+            state.tip_last_ip = ip;
+            state.ip = ip;
+        }
+        state
+    }
+
+    const fn update_ring(&mut self, reason: ProceedInstStopReason) {
+        if let ProceedInstStopReason::FarIndirect {
+            to_ring: Some(ring),
+            ..
+        } = reason
+        {
+            self.ring = Some(ring);
+        }
+    }
+
+    const fn update_mode_exec(&mut self, mode_exec: ModeExec) {
+        self.mode_exec = mode_exec;
+        self.mode_exec_seen = true;
+    }
+
+    /// Push a return address, dropping the oldest one if the stack already holds `depth` of them
+    #[cfg(feature = "retc")]
+    fn push_ret(&mut self, ip: u64, depth: usize) {
+        if depth == 0 {
+            return;
+        }
+        if self.ret_comp_stack.len() >= depth {
+            self.ret_comp_stack.pop_front();
         }
+        self.ret_comp_stack.push_back(ip);
+    }
+
+    /// Returns a [`PtDecoderEvent::PagingChange`] if the PIP changes the CR3
+    fn update_pip(
+        &mut self,
+        pip: Pip,
+        builder: &PtCoverageDecoderBuilder,
+    ) -> Option<PtDecoderEvent> {
+        let new_cr3 = pip.cr3();
+        self.save_coverage = (!builder.filter_vmx_non_root || pip.non_root_vmx())
+            && !builder.excluded_cr3s.contains(&new_cr3);
+
+        let old_cr3 = self.cr3.replace(new_cr3);
+        let non_root_vmx = pip.non_root_vmx();
+        self.pip = pip;
+
+        old_cr3
+            .filter(|&old_cr3| old_cr3 != new_cr3)
+            .map(|old_cr3| PtDecoderEvent::PagingChange {
+                old_cr3,
+                new_cr3,
+                non_root_vmx,
+            })
+    }
+
+    /// Returns a [`PtDecoderEvent::CoreBusRatioChange`] if the CBR changes the core:bus ratio
+    #[cfg(feature = "cbr")]
+    fn update_cbr(&mut self, cbr: Cbr) -> Option<PtDecoderEvent> {
+        let new_ratio = cbr.core_bus_ratio();
+        self.cbr
+            .replace(new_ratio)
+            .filter(|&old_ratio| old_ratio != new_ratio)
+            .map(|old_ratio| PtDecoderEvent::CoreBusRatioChange {
+                old_ratio,
+                new_ratio,
+            })
+    }
+
+    /// Image containing `ip`, in the current execution context.
+    ///
+    /// Images restricted to a CR3 are only used while that CR3 is active, all the images are
+    /// candidates until the first PIP.
+    fn resolve_image<'a>(&self, images: &'a [PtImage], ip: u64) -> Option<&'a PtImage> {
+        self.resolve_image_index(images, ip)
+            .map(|index| &images[index])
+    }
+
+    /// Same as [`Self::resolve_image`], returns the index of the image in `images`
+    fn resolve_image_index(&self, images: &[PtImage], ip: u64) -> Option<usize> {
+        images.iter().position(|image| {
+            image.contains(ip)
+                && (self.cr3.is_none() || image.cr3().is_none() || image.cr3() == self.cr3)
+        })
     }
 
     fn new_inst_decoder<'a>(
         &self,
         images: &'a [PtImage],
+        options: u32,
     ) -> Result<iced_x86::Decoder<'a>, PtDecoderError> {
-        let image = images
-            .iter()
-            .find(|&image| {
-                self.ip >= image.virtual_address_start() && self.ip <= image.virtual_address_end()
-            })
-            .ok_or(PtDecoderError::MissingImage { address: self.ip })?;
+        let missing_image = PtDecoderError::MissingImage { address: self.ip };
+        let image = self
+            .resolve_image(images, self.ip)
+            .ok_or(missing_image.clone())?;
+        let position = usize::try_from(self.ip - image.virtual_address_start())
+            .map_err(|_| missing_image.clone())?;
 
         let mut decoder = iced_x86::Decoder::with_ip(
             self.mode_exec.addressing_mode().into(),
             image.data(),
             self.ip,
-            iced_x86::DecoderOptions::NONE,
+            options,
         );
-        decoder
-            .set_position((decoder.ip() - image.virtual_address_start()) as usize)
-            .expect(
-                "current IP should be in the current image, otherwise we should have Err before",
-            );
+        decoder.set_position(position).map_err(|_| missing_image)?;
 
         #[cfg(feature = "log_instructions")]
         log::trace!(
@@ -164,23 +624,31 @@ impl ExecutionState {
         Ok(decoder)
     }
 
-    /// Position instruction decoder using the image that includes the current IP
+    /// Position instruction decoder at the current IP, without rebuilding it.
+    ///
+    /// Returns None if the current IP is not in the image used by `inst_decoder`.
     fn reposition_inst_decoder<'a>(
         &self,
         mut inst_decoder: iced_x86::Decoder<'a>,
-        images: &'a [PtImage],
-    ) -> Result<iced_x86::Decoder<'a>, PtDecoderError> {
+    ) -> Option<iced_x86::Decoder<'a>> {
+        if inst_decoder.ip() == self.ip && inst_decoder.can_decode() {
+            return Some(inst_decoder);
+        }
+
         let current_image_start = inst_decoder.ip() - inst_decoder.position() as u64;
 
+        // Half-open range check, like PtImage::contains(). set_position() would accept the
+        // position one past the last byte of the image.
         if self.ip >= current_image_start
-            && inst_decoder
-                .set_position((self.ip - current_image_start) as usize)
-                .is_ok()
+            && let Ok(position) = usize::try_from(self.ip - current_image_start)
+            && position < inst_decoder.max_position()
+            && inst_decoder.set_position(position).is_ok()
         {
+            // set_position() does not update the decoder IP, that is used to compute branch targets
             inst_decoder.set_ip(self.ip);
-            Ok(inst_decoder)
+            Some(inst_decoder)
         } else {
-            self.new_inst_decoder(images)
+            None
         }
     }
 }
@@ -191,6 +659,32 @@ impl PtCoverageDecoderBuilder {
             cpu: None,
             images: vec![],
             filter_vmx_non_root: false,
+            check_canonical_ip: false,
+            check_psbplus_ip: false,
+            check_filter_en_ranges: false,
+            userspace_only: false,
+            kernel_address_start: 0xffff_8000_0000_0000,
+            ring_filter: None,
+            skip_unknown_mode: false,
+            tolerate_missing_psbend: false,
+            exclude_aborted_transactions: false,
+            edge_image_indices: false,
+            skip_missing_images: false,
+            cr3_images: vec![],
+            excluded_cr3s: vec![],
+            masked_ranges: vec![],
+            filter_en_ranges: vec![],
+            relative_coverage: false,
+            decoder_options: iced_x86::DecoderOptions::NONE,
+            initial_addressing_mode: AddressingMode::_64,
+            assume_enabled_at: None,
+            skip_initial_psb: true,
+            decode_until_offset: None,
+            instruction_hook: None,
+            trigger_sink: None,
+            integrity_check: None,
+            #[cfg(feature = "retc")]
+            ret_comp_stack_depth: 64,
         }
     }
 
@@ -204,714 +698,2405 @@ impl PtCoverageDecoderBuilder {
         self
     }
 
-    pub fn images(mut self, images: Vec<PtImage>) -> Self {
-        self.images = images;
+    /// Return [`PtDecoderError::NonCanonicalIp`] when an IP reconstructed from a packet is not
+    /// canonical.
+    pub const fn check_canonical_ip(mut self, check_canonical_ip: bool) -> Self {
+        self.check_canonical_ip = check_canonical_ip;
         self
     }
 
-    pub fn build(self) -> Result<PtCoverageDecoder, PtDecoderError> {
-        Ok(PtCoverageDecoder {
-            builder: self,
-            state: ExecutionState::new(),
-            is_syncd: false,
-            proceed_inst_cache: HashMap::new(),
-        })
+    /// Return [`PtDecoderError::PsbPlusIpOutsideImages`] when the FUP IP of a PSB+ is not in any
+    /// image.
+    pub const fn check_psbplus_ip(mut self, check_psbplus_ip: bool) -> Self {
+        self.check_psbplus_ip = check_psbplus_ip;
+        self
     }
-}
 
-impl Default for PtCoverageDecoderBuilder {
-    fn default() -> Self {
-        Self::new()
+    /// Return [`PtDecoderError::FilterEnRangesMismatch`] when a TIP.PGE or TIP.PGD IP disagrees
+    /// with [`Self::filter_en_ranges`].
+    pub const fn check_filter_en_ranges(mut self, check_filter_en_ranges: bool) -> Self {
+        self.check_filter_en_ranges = check_filter_en_ranges;
+        self
     }
-}
 
-impl PtCoverageDecoder {
-    pub fn coverage<CE>(
-        &mut self,
-        pt_trace: &[u8],
-        coverage: &mut [CE],
-    ) -> Result<(), PtDecoderError>
-    where
-        CE: CoverageEntry,
-    {
-        let mut iteration_state = CovDecIterationState::new(self, pt_trace, coverage)?;
+    /// Skip the code from [`Self::kernel_address_start`] on, resuming at the next userspace TIP,
+    /// TIP.PGE or PSB.
+    pub const fn userspace_only(mut self, userspace_only: bool) -> Self {
+        self.userspace_only = userspace_only;
+        self
+    }
 
-        loop {
-            match self.proceed_with_trace(&mut iteration_state) {
-                Ok(()) => continue,
-                Err(PtDecoderError::Eof) => break Ok(()),
-                Err(e) => break Err(e),
-            }
-        }
+    /// First kernel address used by [`Self::userspace_only`], defaults to `0xffff_8000_0000_0000`.
+    pub const fn kernel_address_start(mut self, kernel_address_start: u64) -> Self {
+        self.kernel_address_start = kernel_address_start;
+        self
     }
 
-    /// Continue decoding using PT trace.
-    /// Can consume one or more PT packets.
-    fn proceed_with_trace<CE: CoverageEntry>(
-        &mut self,
-        iteration_state: &mut CovDecIterationState<CE>,
-    ) -> Result<(), PtDecoderError> {
-        let packet = iteration_state.packet_decoder.next_packet()?;
+    /// Record coverage only while executing in `ring`, as inferred from the executed instructions.
+    ///
+    /// The inference has two limits:
+    /// - every IRET is assumed to return to [`Ring::User`], so the code following a kernel to
+    ///   kernel IRET (e.g. a nested interrupt) is attributed to userspace;
+    /// - the ring is unknown until the first transition, everything before it is recorded.
+    pub const fn ring_filter(mut self, ring: Option<Ring>) -> Self {
+        self.ring_filter = ring;
+        self
+    }
 
-        match packet {
-            PtPacket::TntShort(tnt_s) => {
-                self.proceed_inst_tnt(tnt_s.into_iter().into(), iteration_state)?
-            }
-            PtPacket::TntLong(tnt_l) => {
-                self.proceed_inst_tnt(tnt_l.into_iter().into(), iteration_state)?
-            }
-            PtPacket::Tip(tip) => self.proceed_inst_tip(tip, iteration_state)?,
-            PtPacket::TipPge(tip_pge) => self.handle_tip_pge(tip_pge)?,
-            PtPacket::TipPgd(tip_pgd) => self.handle_tip_pgd(tip_pgd)?,
-            PtPacket::Fup(fup) => self.handle_fup(fup, iteration_state)?,
-            PtPacket::Pip(pip) => self.handle_pip(pip)?,
-            PtPacket::ModeExec(mode_exec) => self.handle_mode_exec(mode_exec, iteration_state)?,
-            PtPacket::ModeTsx(mode_tsx) => self.handle_mode_tsx(mode_tsx, iteration_state)?,
-            PtPacket::TraceStop(..) => {} // todo
-            PtPacket::Vmcs(..) => {}      //todo
-            PtPacket::Ovf(..) => self.handle_ovf(iteration_state)?,
-            PtPacket::Psb(..) => self.state = decode_psbplus(iteration_state, self.builder.cpu)?,
-            PtPacket::PsbEnd(psb_end) => {
-                return Err(PtDecoderError::InvalidPacketSequence {
-                    packets: vec![PtPacket::PsbEnd(psb_end)],
-                });
-            }
-            _ => {} // Ignore other packets
-        };
-        Ok(())
+    /// Skip MODE packets with an unknown leaf (e.g. generated by future CPUs) instead of returning
+    /// [`PtDecoderError::UnknownPacket`].
+    pub const fn skip_unknown_mode(mut self, skip_unknown_mode: bool) -> Self {
+        self.skip_unknown_mode = skip_unknown_mode;
+        self
     }
 
-    fn handle_ovf<CE: CoverageEntry>(
-        &mut self,
-        iteration_state: &mut CovDecIterationState<CE>,
-    ) -> Result<(), PtDecoderError> {
-        #[cfg(feature = "retc")]
-        self.state.ret_comp_stack.clear();
+    /// End a PSB+ missing its PSBEND at the first TNT instead of returning
+    /// [`PtDecoderError::MalformedPsbPlus`].
+    pub const fn tolerate_missing_psbend(mut self, tolerate_missing_psbend: bool) -> Self {
+        self.tolerate_missing_psbend = tolerate_missing_psbend;
+        self
+    }
 
-        // state.packet_en might have changed during the overflow
-        match iteration_state.packet_decoder.next_packet()? {
-            PtPacket::Fup(fup) => {
-                self.state.packet_en = true;
-                self.handle_fup_after_ovf(fup)
-            }
-            p => {
-                self.state.packet_en = false;
-                iteration_state.packet_decoder.rollback_one_packet(p)
-            }
-        }
+    /// Discard the coverage recorded inside TSX transactions that abort.
+    pub const fn exclude_aborted_transactions(
+        mut self,
+        exclude_aborted_transactions: bool,
+    ) -> Self {
+        self.exclude_aborted_transactions = exclude_aborted_transactions;
+        self
     }
 
-    fn handle_mode_tsx<CE: CoverageEntry>(
-        &mut self,
-        mode_tsx: ModeTsx,
-        iteration_state: &mut CovDecIterationState<CE>,
-    ) -> Result<(), PtDecoderError> {
-        // todo: double check this function
-        if self.state.packet_en {
-            let fup = match iteration_state.packet_decoder.next_packet()? {
-                PtPacket::Fup(fup) => fup,
-                p => {
-                    return Err(PtDecoderError::InvalidPacketSequence {
-                        packets: vec![PtPacket::ModeTsx(mode_tsx), p],
-                    });
-                }
-            };
-            self.handle_standalone_fup(&fup)?;
+    /// Fill [`Edge::from_image`] and [`Edge::to_image`] of the recorded edges, at the cost of two
+    /// image lookups per edge.
+    pub const fn edge_image_indices(mut self, edge_image_indices: bool) -> Self {
+        self.edge_image_indices = edge_image_indices;
+        self
+    }
 
-            if mode_tsx.transaction_state() == TransactionState::Abort {
-                match iteration_state.packet_decoder.next_packet()? {
-                    PtPacket::Tip(tip) => self.proceed_inst_tip(tip, iteration_state)?,
-                    PtPacket::TipPge(tip_pge) => self.handle_tip_pge(tip_pge)?,
-                    PtPacket::TipPgd(tip_pgd) => self.handle_tip_pgd(tip_pgd)?,
-                    p => {
-                        return Err(PtDecoderError::InvalidPacketSequence {
-                            packets: vec![PtPacket::ModeTsx(mode_tsx), PtPacket::Fup(fup), p],
-                        });
-                    }
-                }
-            }
-        }
+    /// On [`PtDecoderError::MissingImage`], resume at the next TIP.PGE or PSB instead of failing.
+    pub const fn skip_missing_images(mut self, skip_missing_images: bool) -> Self {
+        self.skip_missing_images = skip_missing_images;
+        self
+    }
 
-        self.state.mode_tsx = mode_tsx;
-        Ok(())
+    /// Compute the coverage map entries from the image index and the IP offsets in it, not the
+    /// absolute IPs.
+    pub const fn relative_coverage(mut self, relative_coverage: bool) -> Self {
+        self.relative_coverage = relative_coverage;
+        self
     }
 
-    fn handle_fup_after_ovf(&mut self, fup: Fup) -> Result<(), PtDecoderError> {
-        if !fup.ip(&mut self.state.tip_last_ip) {
-            Err(PtDecoderError::MalformedPacket)
-        } else {
-            self.state.ip = self.state.tip_last_ip;
-            Ok(())
-        }
+    /// Options of the instruction decoder, a combination of [`iced_x86::DecoderOptions`] flags.
+    pub const fn decoder_options(mut self, decoder_options: u32) -> Self {
+        self.decoder_options = decoder_options;
+        self
     }
 
-    fn handle_standalone_fup(&mut self, fup: &Fup) -> Result<(), PtDecoderError> {
-        if !fup.ip(&mut self.state.tip_last_ip) {
-            return Err(PtDecoderError::MalformedPacket);
-        }
+    /// Addressing mode used until the first MODE.Exec packet, default is [`AddressingMode::_64`].
+    pub const fn initial_addressing_mode(mut self, addressing_mode: AddressingMode) -> Self {
+        self.initial_addressing_mode = addressing_mode;
+        self
+    }
 
-        match self.proceed_inst_until(Some(self.state.tip_last_ip))? {
-            ProceedInstStopReason::UntilIpReached => Ok(()),
-            _ => Err(PtDecoderError::IncoherentImage),
-        }
+    /// Decode from the first byte of the trace, with tracing already enabled at `ip`.
+    pub const fn assume_enabled_at(mut self, ip: u64) -> Self {
+        self.assume_enabled_at = Some(ip);
+        self
     }
 
-    fn handle_mode_exec<CE: CoverageEntry>(
-        &mut self,
-        mode_exec: ModeExec,
-        iteration_state: &mut CovDecIterationState<CE>,
-    ) -> Result<(), PtDecoderError> {
-        match iteration_state.packet_decoder.next_packet()? {
-            PtPacket::Tip(tip) => self.proceed_inst_tip(tip, iteration_state)?,
-            PtPacket::TipPge(tip_pge) => self.handle_tip_pge(tip_pge)?,
-            PtPacket::Fup(fup) => self.handle_standalone_fup(&fup)?,
-            p => {
-                return Err(PtDecoderError::InvalidPacketSequence {
-                    packets: vec![PtPacket::ModeExec(mode_exec), p],
-                });
-            }
-        }
-        self.state.mode_exec = mode_exec;
+    /// Skip the bytes preceding the first PSB of the trace, default is true.
+    pub const fn skip_initial_psb(mut self, skip_initial_psb: bool) -> Self {
+        self.skip_initial_psb = skip_initial_psb;
+        self
+    }
 
-        Ok(())
+    /// Stop decoding, successfully, at the first packet starting at or after `offset`.
+    pub const fn decode_until_offset(mut self, offset: usize) -> Self {
+        self.decode_until_offset = Some(offset);
+        self
     }
 
-    fn handle_async_pip(&mut self, pip: Pip) {
-        // todo: The purpose of the PIP is to indicate to the decoder which application is running,
-        // so that it can apply the proper binaries to the linear addresses that are being traced.
-        // add decoder cr3 filtering
+    /// Whether decoding starts at the first byte of the trace, without searching a PSB
+    const fn starts_syncd(&self) -> bool {
+        !self.skip_initial_psb || self.assume_enabled_at.is_some()
+    }
 
-        if self.builder.filter_vmx_non_root {
-            self.state.save_coverage = pip.non_root_vmx();
-        }
+    /// Maximum number of return addresses kept for return compression, default is 64.
+    ///
+    /// A compressed return whose address was dropped fails with
+    /// [`PtDecoderError::EmptyReturnStack`].
+    #[cfg(feature = "retc")]
+    pub const fn ret_comp_stack_depth(mut self, depth: usize) -> Self {
+        self.ret_comp_stack_depth = depth;
+        self
+    }
 
-        self.state.pip = pip;
+    pub fn images(mut self, images: Vec<PtImage>) -> Self {
+        self.images = images;
+        self
     }
 
-    fn handle_pip(&mut self, pip: Pip) -> Result<(), PtDecoderError> {
-        use ProceedInstStopReason::*;
+    /// Images of each address space, used while PIP packets report its CR3.
+    pub fn cr3_images(mut self, cr3_images: Vec<(u64, Vec<PtImage>)>) -> Self {
+        self.cr3_images = cr3_images;
+        self
+    }
 
-        if self.state.packet_en {
-            match self.proceed_inst_until(None)? {
-                MovCr3 | FarIndirect => {}
-                CondBranch { .. } | Indirect | Return => {
-                    return Err(PtDecoderError::IncoherentImage);
-                }
-                UntilIpReached => unreachable!("until parameter is set to None"),
+    /// Call `hook` with each instruction walked while decoding, bypassing the decoding caches.
+    pub fn instruction_hook(mut self, hook: impl FnMut(&Instruction) + Send + 'static) -> Self {
+        self.instruction_hook = Some(Callback(Arc::new(Mutex::new(hook))));
+        self
+    }
+
+    /// Call `sink` with the [`Trigger`] reported by each TRIG packet.
+    pub fn trigger_sink(mut self, sink: impl FnMut(&Trigger) + Send + 'static) -> Self {
+        self.trigger_sink = Some(Callback(Arc::new(Mutex::new(sink))));
+        self
+    }
+
+    /// Call `check` with each PSB-delimited segment of the trace before decoding it, failing with
+    /// [`PtDecoderError::IntegrityCheckFailed`] if it returns false.
+    pub fn integrity_check(mut self, check: impl FnMut(&[u8]) -> bool + Send + 'static) -> Self {
+        self.integrity_check = Some(Callback(Arc::new(Mutex::new(check))));
+        self
+    }
+
+    /// Do not record coverage while PIP packets report `cr3`, can be called multiple times.
+    pub fn exclude_cr3(mut self, cr3: u64) -> Self {
+        self.excluded_cr3s.push(cr3);
+        self
+    }
+
+    /// Do not record the edges whose target is in `start..end`, can be called multiple times.
+    pub fn mask_range(mut self, start: u64, end: u64) -> Self {
+        self.masked_ranges.push((start, end));
+        self
+    }
+
+    /// IP filter ranges (FilterEn) the trace was recorded with, as `start..end` pairs.
+    pub fn filter_en_ranges(mut self, ranges: &[(u64, u64)]) -> Self {
+        self.filter_en_ranges = ranges.to_vec();
+        self
+    }
+
+    /// Decode `pt_trace` without computing any coverage.
+    ///
+    /// Useful to check that a trace decodes cleanly against the configured images.
+    pub fn validate(self, pt_trace: &[u8]) -> Result<(), PtDecoderError> {
+        let mut decoder = self.build()?;
+        let iteration_state = CovDecIterationState::<u8>::new(&mut decoder, pt_trace, None)?;
+        decoder.decode(iteration_state)
+    }
+
+    /// Address ranges the trace jumps to, as sorted and coalesced half-open `(start, end)` ranges.
+    ///
+    /// Only the packets are decoded, no image is needed: the ranges contain the IPs carried by
+    /// TIP, TIP.PGE, TIP.PGD and FUP packets, not the code executed after them. An IP at most
+    /// `max_gap` bytes past the end of a range extends it, e.g. to merge the IPs of a function.
+    /// Useful to find out which images are missing.
+    pub fn required_image_ranges(
+        &self,
+        pt_trace: &[u8],
+        max_gap: u64,
+    ) -> Result<Vec<(u64, u64)>, PtDecoderError> {
+        let mut ips = BTreeSet::new();
+        self.for_each_packet_ip(pt_trace, |_, ip| {
+            if let Some(ip) = ip {
+                ips.insert(ip);
+            }
+        })?;
+
+        let mut ranges: Vec<(u64, u64)> = vec![];
+        for ip in ips {
+            match ranges.last_mut() {
+                Some((_, end)) if ip <= end.saturating_add(max_gap) => *end = ip.saturating_add(1),
+                _ => ranges.push((ip, ip.saturating_add(1))),
             }
         }
+        Ok(ranges)
+    }
 
-        self.handle_async_pip(pip);
-        Ok(())
+    /// Edges between consecutive IPs carried by TIP and FUP packets, without walking the code.
+    ///
+    /// No image is needed, but TNTs cannot be followed: only the indirect branches, returns and
+    /// asynchronous events of the trace are reported, e.g. to build a call graph. A disabled
+    /// trace, a suppressed IP or an overflow break the chain. The FUPs inside PSB+ only carry the
+    /// current IP and are ignored.
+    pub fn tip_edges(&self, pt_trace: &[u8]) -> Result<Vec<(u64, u64)>, PtDecoderError> {
+        let mut edges = vec![];
+        let mut last_target = None;
+        let mut in_psbplus = false;
+        self.for_each_packet_ip(pt_trace, |packet, ip| match (packet, ip) {
+            (PtPacket::Psb(..), _) => in_psbplus = true,
+            (PtPacket::PsbEnd(..), _) => in_psbplus = false,
+            (PtPacket::Fup(..), _) if in_psbplus => {}
+            (PtPacket::Tip(..) | PtPacket::Fup(..), Some(ip)) => {
+                if let Some(from) = last_target {
+                    edges.push((from, ip));
+                }
+                last_target = Some(ip);
+            }
+            (PtPacket::TipPge(..), Some(ip)) => last_target = Some(ip),
+            (PtPacket::TipPgd(..) | PtPacket::Ovf(..), _)
+            | (PtPacket::Tip(..) | PtPacket::TipPge(..) | PtPacket::Fup(..), None) => {
+                last_target = None
+            }
+            _ => {}
+        })?;
+        Ok(edges)
     }
 
-    fn handle_fup<CE: CoverageEntry>(
-        &mut self,
-        fup: Fup,
-        iteration_state: &mut CovDecIterationState<CE>,
+    /// Decode the packets of `pt_trace`, calling `f` with each packet and the IP it carries, if
+    /// any.
+    fn for_each_packet_ip(
+        &self,
+        pt_trace: &[u8],
+        mut f: impl FnMut(&PtPacket, Option<u64>),
     ) -> Result<(), PtDecoderError> {
-        self.handle_standalone_fup(&fup)?;
+        let mut packet_decoder =
+            PtPacketDecoder::new(pt_trace)?.skip_unknown_mode(self.skip_unknown_mode);
+        let mut last_ip = 0;
         loop {
-            let packet = iteration_state.packet_decoder.next_packet()?;
-            match packet {
-                PtPacket::Pip(pip) => self.handle_async_pip(pip),
-                PtPacket::Vmcs(..) => todo!("handle fup vmcs"),
-                PtPacket::ModeExec(..) => todo!("handle fup mode exec"),
-                PtPacket::Tip(tip) => break self.handle_async_tip(tip)?,
-                PtPacket::TipPgd(tip_pgd) => break self.handle_async_tip_pgd(tip_pgd),
-                p => {
-                    // todo handle overflow packet here (and in other InvalidPacketSequence?)
-                    return Err(PtDecoderError::InvalidPacketSequence {
-                        packets: vec![PtPacket::Fup(fup), p],
-                    });
+            let packet = match packet_decoder.next_packet() {
+                Ok(packet) => packet,
+                Err(PtDecoderError::Eof) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let ip = match &packet {
+                PtPacket::Tip(tip)
+                | PtPacket::TipPge(tip)
+                | PtPacket::TipPgd(tip)
+                | PtPacket::Fup(tip) => tip.ip(&mut last_ip).then_some(last_ip),
+                // SDM 34.4.2.2 “Last IP” is reset by PSBs
+                PtPacket::Psb(..) => {
+                    last_ip = 0;
+                    None
                 }
-            }
+                _ => None,
+            };
+            f(&packet, ip);
         }
-        Ok(())
     }
 
-    fn handle_async_tip(&mut self, tip: Tip) -> Result<(), PtDecoderError> {
-        if tip.ip(&mut self.state.tip_last_ip) {
-            self.state.ip = self.state.tip_last_ip;
-            Ok(())
-        } else {
-            // we jumped somewhere but who knows where?
-            Err(PtDecoderError::InvalidPacketSequence {
-                packets: vec![PtPacket::Tip(tip)],
-            })
-        }
+    pub fn build(self) -> Result<PtCoverageDecoder, PtDecoderError> {
+        self.build_with_scratch(DecoderScratch::new())
     }
 
-    fn handle_async_tip_pgd(&mut self, tip_pgd: TipPgd) {
-        self.state.packet_en = false;
-        if tip_pgd.ip(&mut self.state.tip_last_ip) {
-            self.state.ip = self.state.tip_last_ip;
+    /// Same as [`Self::build`], reusing the buffers of `scratch`.
+    pub fn build_with_scratch(
+        mut self,
+        scratch: DecoderScratch,
+    ) -> Result<PtCoverageDecoder, PtDecoderError> {
+        for (cr3, images) in mem::take(&mut self.cr3_images) {
+            self.images
+                .extend(images.into_iter().map(|image| image.with_cr3(cr3)));
         }
-    }
 
-    fn handle_tip_pgd(&mut self, tip_pgd: TipPgd) -> Result<(), PtDecoderError> {
-        use ProceedInstStopReason::*;
+        if self.images.iter().any(|image| {
+            image
+                .virtual_address_start()
+                .checked_add(image.data().len() as u64)
+                .is_none()
+        }) {
+            return Err(PtDecoderError::ImageTooLarge);
+        }
 
-        let ret = if tip_pgd.ip(&mut self.state.tip_last_ip) {
-            match self.proceed_inst_until(Some(self.state.tip_last_ip))? {
-                CondBranch { .. } | Indirect | FarIndirect | UntilIpReached | Return => Ok(()),
-                MovCr3 => Err(PtDecoderError::IncoherentImage),
-            }
-        } else {
-            // might be caused by:
-            // - trace stopped manually or operational error probably there is no way to get the
-            //   precise ip where tracing actually stopped... Let's ignore this case.
-            // - a conditional branch (replaces TNT)
-            // - Change of CPL/CR3
-            match self.proceed_inst_until(None)? {
-                CondBranch { .. } | Indirect | FarIndirect | MovCr3 | Return => Ok(()),
-                UntilIpReached => unreachable!("until parameter is set to None"),
-            }
-        };
+        #[cfg_attr(not(feature = "retc"), expect(unused_mut))]
+        let mut state = ExecutionState::initial(&self);
+        #[cfg(feature = "retc")]
+        {
+            state.ret_comp_stack = scratch.ret_comp_stack;
+        }
 
-        self.state.packet_en = false;
-        ret
+        Ok(PtCoverageDecoder {
+            state,
+            is_syncd: self.starts_syncd(),
+            builder: self,
+            proceed_inst_cache: scratch.proceed_inst_cache,
+            #[cfg(feature = "cache")]
+            inst_cache: scratch.inst_cache,
+            #[cfg(feature = "log_coverage")]
+            coverage_entry_edges: scratch.coverage_entry_edges,
+            transaction_coverage: scratch.transaction_coverage,
+            instruction_ips: None,
+            trace_position: 0,
+            trace_len: 0,
+            #[cfg(test)]
+            inst_decoder_builds: 0,
+        })
     }
+}
 
-    fn handle_tip_pge(&mut self, tip_pge: TipPge) -> Result<(), PtDecoderError> {
-        if tip_pge.ip(&mut self.state.tip_last_ip) {
-            self.state.packet_en = true;
-            self.state.ip = self.state.tip_last_ip;
-            Ok(())
-        } else {
-            Err(PtDecoderError::MalformedPacket)
-        }
+impl Default for PtCoverageDecoderBuilder {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    fn proceed_inst_tip<CE: CoverageEntry>(
-        &mut self,
-        tip: Tip,
-        iteration_state: &mut CovDecIterationState<CE>,
-    ) -> Result<(), PtDecoderError> {
-        use ProceedInstStopReason::*;
+/// Decoder of many independent traces of the same program, e.g. the executions of a fuzzer.
+///
+/// The images are prepared once, and the decoding caches stay warm from one trace to the next,
+/// while each trace is decoded from a clean state as by a newly built [`PtCoverageDecoder`].
+#[derive(Debug)]
+pub struct PtCoverageSession {
+    decoder: PtCoverageDecoder,
+}
 
-        match self.proceed_inst_until(None)? {
-            Indirect | FarIndirect | Return => {
-                if tip.ip(&mut self.state.tip_last_ip) {
-                    self.add_coverage_entry(self.state.tip_last_ip, iteration_state);
-                    self.state.ip = self.state.tip_last_ip;
-                    Ok(())
-                } else {
-                    Err(PtDecoderError::MalformedPacket)
-                }
-            }
-            CondBranch { .. } | MovCr3 => Err(PtDecoderError::IncoherentImage),
-            UntilIpReached => unreachable!("until parameter is set to None"),
-        }
+impl PtCoverageSession {
+    pub fn new(builder: PtCoverageDecoderBuilder) -> Result<Self, PtDecoderError> {
+        Ok(Self {
+            decoder: builder.build()?,
+        })
     }
 
-    /// Proceed decoding the instructions until next decision point, considering the current PT
-    /// packet is a TNT
-    fn proceed_inst_tnt<CE: CoverageEntry>(
+    /// Decode the whole `pt_trace`, adding the executed edges to `coverage`.
+    ///
+    /// Takes `&mut self` because the decoding caches are filled along the way. See
+    /// [`PtCoverageDecoder::coverage`].
+    pub fn decode<CE: CoverageEntry>(
         &mut self,
-        tnt_iter: TntIter,
-        iteration_state: &mut CovDecIterationState<CE>,
+        pt_trace: &[u8],
+        coverage: &mut [CE],
     ) -> Result<(), PtDecoderError> {
-        use ProceedInstStopReason::*;
-        #[cfg(feature = "log_packets")]
-        log::trace!("TNT handling start");
+        self.decoder.reset();
+        self.decoder.coverage(pt_trace, coverage)
+    }
+}
 
-        for tnt in tnt_iter {
-            'inst: loop {
-                match self.proceed_inst_until(None)? {
-                    // TNT consumed at the current decision point
-                    CondBranch { to } => {
-                        if tnt {
-                            self.add_coverage_entry(to, iteration_state);
-                            self.state.ip = to;
-                            #[cfg(feature = "log_packets")]
-                            log::trace!("TNT taken to 0x{:x}", self.state.ip);
-                        } else {
-                            #[cfg(feature = "log_packets")]
-                            log::trace!("TNT not taken");
-                        }
-                        break 'inst;
-                    }
-                    #[cfg(feature = "retc")]
-                    Return => {
-                        if tnt {
-                            let to = self.state.ret_comp_stack.pop().expect("empty ret stack"); //todo better error handling
-                            let to_masked = match self.state.mode_exec.addressing_mode() {
-                                AddressingMode::_16 => to & u16::MAX as u64,
-                                AddressingMode::_32 => to & u32::MAX as u64,
-                                AddressingMode::_64 => to,
-                            };
-                            self.add_coverage_entry(to_masked, iteration_state);
-                            self.state.ip = to_masked;
-                            #[cfg(feature = "log_packets")]
-                            log::trace!("TNT taken (return compression) to 0x{:x}", self.state.ip);
-                        } else {
-                            todo!("better error: broken return compression")
-                        }
-                        break 'inst;
-                    }
+/// Decoding of a trace one recorded edge at a time, see [`PtCoverageDecoder::edge_stepper`].
+///
+/// The stepping keeps a packet decoder over the borrowed trace between two edges, so it lives in
+/// this type rather than in a `next_edge` method of the decoder. The decoder is borrowed until the
+/// stepper is dropped, [`PtCoverageDecoder::progress`] can be read afterwards.
+#[derive(Debug)]
+pub struct PtEdgeStepper<'d, 't> {
+    decoder: &'d mut PtCoverageDecoder,
+    /// None once the whole trace is decoded
+    packet_decoder: Option<PtPacketDecoder<'t>>,
+    end: usize,
+    /// Edges decoded but not yet returned
+    pending_edges: VecDeque<(u64, u64)>,
+}
 
-                    // TNT NOT consumed at the current decision point, handle the decision point
-                    // and continue in the loop without consuming the TNT
-                    #[cfg_attr(feature = "retc", expect(unreachable_patterns))]
-                    Indirect | FarIndirect | Return => {
-                        // handle possible deferred tips
-                        let deferred = iteration_state.packet_decoder.next_packet()?;
-                        let tip = if let PtPacket::Tip(tip) = deferred {
-                            tip
-                        } else {
-                            return Err(PtDecoderError::InvalidPacketSequence {
-                                packets: vec![deferred],
-                            }); // todo add tnt here to the sequence
-                        };
+impl PtEdgeStepper<'_, '_> {
+    /// Decode up to the next recorded edge, returning its `(from, to)` pair, or None at the end of
+    /// the trace.
+    pub fn next_edge(&mut self) -> Result<Option<(u64, u64)>, PtDecoderError> {
+        let mut edges = vec![];
+        while self.pending_edges.is_empty() {
+            let Some(packet_decoder) = self.packet_decoder.take() else {
+                return Ok(None);
+            };
+            let mut iteration_state = CovDecIterationState::<u8> {
+                packet_decoder,
+                coverage: None,
+                image_coverages: None,
+                new_entries: None,
+                edges: Some(&mut edges),
+                events: None,
+                indirect_calls: None,
+                end: self.end,
+            };
 
-                        if tip.ip(&mut self.state.tip_last_ip) {
-                            self.add_coverage_entry(self.state.tip_last_ip, iteration_state);
-                            self.state.ip = self.state.tip_last_ip;
-                        } else {
-                            return Err(PtDecoderError::MalformedPacket);
-                        };
+            let ret = self.decoder.decode_step(&mut iteration_state);
+            let packet_decoder = iteration_state.packet_decoder;
+            self.decoder.trace_position = packet_decoder.last_packet_range().end;
+            self.packet_decoder = Some(packet_decoder);
+            match ret {
+                Ok(()) => {}
+                Err(PtDecoderError::Eof) => {
+                    self.packet_decoder = None;
+                    // The trailing padding is skipped, unless stopped by decode_until_offset
+                    if self.decoder.trace_position < self.end {
+                        self.decoder.trace_position = self.decoder.trace_len;
                     }
-                    MovCr3 => return Err(PtDecoderError::IncoherentImage),
-                    UntilIpReached => unreachable!("until parameter is set to None"),
+                    return Ok(None);
                 }
+                Err(e) => return Err(e),
             }
+            self.pending_edges
+                .extend(edges.drain(..).map(|edge| (edge.from, edge.to)));
         }
-
-        #[cfg(feature = "log_packets")]
-        log::trace!("TNT handling end");
-        Ok(())
+        Ok(self.pending_edges.pop_front())
     }
+}
 
-    fn proceed_inst_until(
+impl PtCoverageDecoder {
+    /// Decode `pt_trace`, recording the executed edges in `coverage`.
+    ///
+    /// The map is only borrowed for the duration of the call, it is updated by decoding only.
+    /// When a trace is decoded in chunks, the partial coverage can be read between the calls.
+    pub fn coverage<CE>(
         &mut self,
-        until: Option<u64>,
-    ) -> Result<ProceedInstStopReason, PtDecoderError> {
-        use ProceedInstStopReason::*;
-
-        if !self.state.packet_en {
-            return Err(PtDecoderError::IncoherentState);
+        pt_trace: &[u8],
+        coverage: &mut [CE],
+    ) -> Result<(), PtDecoderError>
+    where
+        CE: CoverageEntry,
+    {
+        if coverage.is_empty() {
+            return Err(PtDecoderError::InvalidArgument);
         }
 
-        // Use cache (only if until is None)
-        if until.is_none()
-            && let Some(&(ip, reason)) = self.proceed_inst_cache.get(&self.state.ip)
-        {
-            #[cfg(feature = "log_instructions")]
-            log::trace!(
-                "Cache hit: skipping disassembling from 0x{:x} to 0x{ip:x}",
-                self.state.ip
-            );
-            self.state.ip = ip;
-            return Ok(reason);
+        let iteration_state = CovDecIterationState::new(self, pt_trace, Some(coverage))?;
+        self.decode(iteration_state)
+    }
+
+    /// Same as [`Self::coverage`], additionally appends to `new_entries` the indexes of the entries
+    /// hit for the first time.
+    pub fn coverage_with_new_entries<CE>(
+        &mut self,
+        pt_trace: &[u8],
+        coverage: &mut [CE],
+        new_entries: &mut Vec<usize>,
+    ) -> Result<(), PtDecoderError>
+    where
+        CE: CoverageEntry,
+    {
+        if coverage.is_empty() {
+            return Err(PtDecoderError::InvalidArgument);
         }
 
-        let from = self.state.ip;
-        let mut inst_decoder = self.state.new_inst_decoder(&self.builder.images)?;
-        let ins = loop {
-            if let Some(ip) = until
-                && inst_decoder.ip() == ip
-            {
-                return Ok(UntilIpReached);
-            }
+        let mut iteration_state = CovDecIterationState::new(self, pt_trace, Some(coverage))?;
+        iteration_state.new_entries = Some(new_entries);
+        self.decode(iteration_state)
+    }
 
-            if !inst_decoder.can_decode() {
-                inst_decoder = self
-                    .state
-                    .reposition_inst_decoder(inst_decoder, &self.builder.images)?;
-            }
+    /// Same as [`Self::coverage`], additionally appends to `edges` the recorded edges, in hit
+    /// order.
+    pub fn coverage_with_edges<CE>(
+        &mut self,
+        pt_trace: &[u8],
+        coverage: &mut [CE],
+        edges: &mut Vec<Edge>,
+    ) -> Result<(), PtDecoderError>
+    where
+        CE: CoverageEntry,
+    {
+        if coverage.is_empty() {
+            return Err(PtDecoderError::InvalidArgument);
+        }
 
-            let ins = inst_decoder.decode();
-            #[cfg(feature = "log_instructions")]
-            log::trace!(
-                "\tip: 0x{:x}: {:?} {:?} raw: {:x?}",
-                inst_decoder.ip() - ins.len() as u64,
-                ins.code(),
-                ins.op0_kind(),
-                &self.builder.images[0].data()
-                    [inst_decoder.position()..inst_decoder.position() + ins.len()],
-            );
-            if ins.is_invalid() {
-                return Err(PtDecoderError::MalformedInstruction);
-            }
-            // todo log call for retcomp
+        let mut iteration_state = CovDecIterationState::new(self, pt_trace, Some(coverage))?;
+        iteration_state.edges = Some(edges);
+        self.decode(iteration_state)
+    }
 
-            match next_ip(&ins) {
-                Ok(None) => {}
-                Ok(Some(ip)) => {
-                    #[cfg(feature = "retc")]
-                    self.state.ret_comp_stack.push(inst_decoder.ip());
-                    #[cfg(all(feature = "retc", feature = "log_packets"))]
-                    log::trace!("Pushed on retc stack: 0x{:x}", inst_decoder.ip());
-                    self.state.ip = ip;
-                    inst_decoder = self
-                        .state
-                        .reposition_inst_decoder(inst_decoder, &self.builder.images)?;
-                }
-                Err(()) => {
-                    self.state.ip = inst_decoder.ip();
-                    break ins;
-                }
-            }
-        };
+    /// Same as [`Self::coverage`], additionally appends to `events` the decoding events, in trace
+    /// order.
+    pub fn coverage_with_events<CE>(
+        &mut self,
+        pt_trace: &[u8],
+        coverage: &mut [CE],
+        events: &mut Vec<PtDecoderEvent>,
+    ) -> Result<(), PtDecoderError>
+    where
+        CE: CoverageEntry,
+    {
+        if coverage.is_empty() {
+            return Err(PtDecoderError::InvalidArgument);
+        }
 
-        let ret = match InstructionClass::from(&ins) {
-            InstructionClass::CondBranch => CondBranch {
-                to: ins.near_branch64(),
-            },
-            InstructionClass::Return => Return,
-            InstructionClass::JumpIndirect | InstructionClass::CallIndirect => Indirect,
-            InstructionClass::FarCall | InstructionClass::FarReturn | InstructionClass::FarJump => {
-                FarIndirect
-            }
-            InstructionClass::MovCr3 => MovCr3,
-            InstructionClass::JumpDirect
-            | InstructionClass::CallDirect
-            | InstructionClass::Other => unreachable!("These instructions do not need traces"),
-        };
+        let mut iteration_state = CovDecIterationState::new(self, pt_trace, Some(coverage))?;
+        iteration_state.events = Some(events);
+        self.decode(iteration_state)
+    }
 
-        self.proceed_inst_cache.insert(from, (self.state.ip, ret));
-        Ok(ret)
+    /// Same as [`Self::coverage`], additionally appends to `calls` the `(from, to)` edges of
+    /// indirect and far calls.
+    pub fn coverage_with_indirect_calls<CE>(
+        &mut self,
+        pt_trace: &[u8],
+        coverage: &mut [CE],
+        calls: &mut Vec<(u64, u64)>,
+    ) -> Result<(), PtDecoderError>
+    where
+        CE: CoverageEntry,
+    {
+        if coverage.is_empty() {
+            return Err(PtDecoderError::InvalidArgument);
+        }
+
+        let mut iteration_state = CovDecIterationState::new(self, pt_trace, Some(coverage))?;
+        iteration_state.indirect_calls = Some(calls);
+        self.decode(iteration_state)
     }
 
-    fn add_coverage_entry<CE: CoverageEntry>(
+    /// Same as [`Self::coverage`], with one coverage map per image, in the order of the builder
+    /// images.
+    pub fn coverage_per_image<CE>(
         &mut self,
-        to_ip: u64,
-        iteration_state: &mut CovDecIterationState<CE>,
-    ) {
-        if self.state.save_coverage {
-            let cov_entry = coverage_entry(self.state.ip, to_ip, iteration_state.coverage.len());
-            iteration_state.coverage[cov_entry] =
-                iteration_state.coverage[cov_entry].saturating_add(&1.into());
+        pt_trace: &[u8],
+        coverages: &mut [&mut [CE]],
+    ) -> Result<(), PtDecoderError>
+    where
+        CE: CoverageEntry,
+    {
+        if coverages.len() != self.builder.images.len() {
+            return Err(PtDecoderError::InvalidArgument);
         }
+
+        let mut iteration_state = CovDecIterationState::new(self, pt_trace, None)?;
+        iteration_state.image_coverages = Some(coverages);
+        self.decode(iteration_state)
     }
-}
 
-/// Retuns Ok(Some(ip)) if it can compute next ip from instruction, and it is not the subsequent
-/// instruction in the code. Returns Ok(None) if the next instruction is the following in the code.
-///Returns Err if decoding needs trace to proceed.
-fn next_ip(ins: &Instruction) -> Result<Option<u64>, ()> {
-    match InstructionClass::from(ins) {
-        InstructionClass::Other => Ok(None),
-        InstructionClass::JumpDirect | InstructionClass::CallDirect => {
-            let target = ins.near_branch_target();
-            if target == ins.next_ip() {
-                Ok(None)
-            } else {
-                Ok(Some(target))
-            }
+    /// Same as [`Self::coverage`], additionally appends to `ips` the IP of each walked instruction.
+    pub fn coverage_with_instruction_ips<CE>(
+        &mut self,
+        pt_trace: &[u8],
+        coverage: &mut [CE],
+        ips: &mut Vec<u64>,
+    ) -> Result<(), PtDecoderError>
+    where
+        CE: CoverageEntry,
+    {
+        if coverage.is_empty() {
+            return Err(PtDecoderError::InvalidArgument);
         }
-        InstructionClass::JumpIndirect
-        | InstructionClass::MovCr3
-        | InstructionClass::CallIndirect
-        | InstructionClass::CondBranch
-        | InstructionClass::FarCall
-        | InstructionClass::FarJump
-        | InstructionClass::FarReturn
-        | InstructionClass::Return => Err(()),
+
+        let iteration_state = CovDecIterationState::new(self, pt_trace, Some(coverage))?;
+        self.instruction_ips = Some(mem::take(ips));
+        let ret = self.decode(iteration_state);
+        *ips = self.instruction_ips.take().unwrap_or_default();
+        ret
     }
-}
 
-const fn coverage_entry(from: u64, to: u64, map_len: usize) -> usize {
-    (fmix64(from) ^ fmix64(to)) as usize % map_len
-}
+    /// Decode `pt_trace` without computing any coverage, returning the last reconstructed IP.
+    ///
+    /// The IP is the one reached at the last decision point consumed by the trace: the code
+    /// following it is not walked. If the trace ends in the middle of a TNT sequence, i.e. more
+    /// conditional branches follow in the next trace, the IP is the target of the last branch
+    /// whose TNT bit is in `pt_trace`.
+    pub fn run_to_end(&mut self, pt_trace: &[u8]) -> Result<u64, PtDecoderError> {
+        let iteration_state = CovDecIterationState::<u8>::new(self, pt_trace, None)?;
+        self.decode(iteration_state)?;
+        Ok(self.state.ip)
+    }
 
-fn decode_psbplus<CE: Debug>(
-    iteration_state: &mut CovDecIterationState<CE>,
-    cpu: Option<PtCpu>,
-) -> Result<ExecutionState, PtDecoderError> {
-    let mut state = ExecutionState::new();
+    /// Decode `pt_trace` one recorded edge at a time, see [`PtEdgeStepper::next_edge`].
+    pub fn edge_stepper<'t>(
+        &mut self,
+        pt_trace: &'t [u8],
+    ) -> Result<PtEdgeStepper<'_, 't>, PtDecoderError> {
+        let CovDecIterationState {
+            packet_decoder,
+            end,
+            ..
+        } = CovDecIterationState::<u8>::new(self, pt_trace, None)?;
+        self.trace_len = pt_trace.len();
+        self.trace_position = 0;
 
-    loop {
-        match iteration_state.packet_decoder.next_packet()? {
-            PtPacket::PsbEnd(..) => return Ok(state),
-            #[cfg(feature = "tsc")]
-            PtPacket::Tsc(..) => todo!(),
-            #[cfg(all(feature = "tsc", feature = "mtc"))]
-            PtPacket::Tma(..) => todo!(),
-            PtPacket::Pip(pip) => state.pip = pip,
-            PtPacket::Vmcs(vmcs) => state.vmcs = Some(vmcs),
-            PtPacket::ModeTsx(mode_tsx) => state.mode_tsx = mode_tsx,
-            PtPacket::ModeExec(mode_exec) => state.mode_exec = mode_exec,
-            PtPacket::Fup(fup) => {
-                // fixme: if the decoder was already running, consider also that some code executed
-                // between PSB's preceeding packet and PSB might get ignored here
-                if let Some(last_ip) = decode_psbplus_fup(fup, state.tip_last_ip, cpu) {
-                    state.packet_en = true;
-                    state.tip_last_ip = last_ip;
-                    state.ip = last_ip;
-                } else {
-                    state.packet_en = false;
-                }
-            }
-            #[cfg(feature = "cyc")]
-            PtPacket::Cyc(..) => todo!(),
-            #[cfg(feature = "mtc")]
-            PtPacket::Mtc(..) => todo!(),
-            PtPacket::Ovf(..) => todo!(),
-            _ => return Err(PtDecoderError::MalformedPsbPlus),
-        }
+        Ok(PtEdgeStepper {
+            decoder: self,
+            packet_decoder: Some(packet_decoder),
+            end,
+            pending_edges: VecDeque::new(),
+        })
     }
-}
 
-fn decode_psbplus_fup(fup: Fup, mut last_ip: u64, cpu: Option<PtCpu>) -> Option<u64> {
-    if let Some(cpu) = cpu
-        && cpu.errata().bdm70
-    {
-        // todo: pt_evt_check_bdm70
+    /// Forget the decoding state of the previous trace, keeping the caches
+    fn reset(&mut self) {
+        #[cfg_attr(not(feature = "retc"), expect(unused_mut))]
+        let mut state = ExecutionState::initial(&self.builder);
+        #[cfg(feature = "retc")]
+        {
+            state.ret_comp_stack = mem::take(&mut self.state.ret_comp_stack);
+            state.ret_comp_stack.clear();
+        }
+        self.state = state;
+        self.is_syncd = self.builder.starts_syncd();
+        self.transaction_coverage.clear();
+        self.trace_position = 0;
+        self.trace_len = 0;
     }
-    fup.ip(&mut last_ip).then_some(last_ip)
-}
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum InstructionClass {
-    Other,
+    /// Length of the trace passed to the last decoding call, 0 before the first one.
+    pub const fn trace_len(&self) -> usize {
+        self.trace_len
+    }
 
-    CallDirect,
-    CallIndirect,
-    CondBranch,
-    FarCall,
-    FarJump,
-    FarReturn,
-    JumpDirect,
-    JumpIndirect,
-    MovCr3,
-    Return,
-}
+    /// Fraction of the trace passed to the last decoding call that has been decoded, from 0.0 to
+    /// 1.0, e.g. to report how far a [`PtEdgeStepper`] got once it is dropped.
+    ///
+    /// The position is the end of the last decoded packet: the bytes preceding the first PSB and
+    /// the trailing padding are counted once skipped.
+    pub fn progress(&self) -> f32 {
+        if self.trace_len == 0 {
+            return 0.0;
+        }
+        self.trace_position as f32 / self.trace_len as f32
+    }
 
-impl From<&Instruction> for InstructionClass {
-    fn from(instruction: &Instruction) -> Self {
-        match instruction.flow_control() {
-            FlowControl::Next => {
-                if matches!(instruction.code(), Code::Mov_cr_r32 | Code::Mov_cr_r64)
-                    && instruction.op0_register() == Register::CR3
-                {
-                    Self::MovCr3
-                } else {
-                    Self::Other
-                }
-            }
-            FlowControl::ConditionalBranch => Self::CondBranch,
-            FlowControl::UnconditionalBranch => {
-                if instruction.is_jmp_far() {
-                    Self::FarJump
-                } else {
-                    Self::JumpDirect
-                }
-            }
-            FlowControl::Interrupt | FlowControl::Exception => Self::FarCall,
-            FlowControl::Return => match instruction.code() {
-                Code::Retnd
-                | Code::Retnq
-                | Code::Retnw
-                | Code::Retnd_imm16
-                | Code::Retnq_imm16
-                | Code::Retnw_imm16 => Self::Return,
-                _ => Self::FarReturn,
-            },
-            FlowControl::IndirectBranch => {
-                if instruction.is_jmp_far_indirect() {
-                    Self::FarJump
-                } else {
-                    Self::JumpIndirect
-                }
-            }
-            FlowControl::Call => {
-                if instruction.is_call_near() {
-                    return Self::CallDirect;
-                }
+    /// Release the buffers of the decoder, to reuse them with
+    /// [`PtCoverageDecoderBuilder::build_with_scratch`].
+    pub fn into_scratch(mut self) -> DecoderScratch {
+        // The cached instructions belong to the images of this decoder
+        self.proceed_inst_cache.clear();
+        #[cfg(feature = "cache")]
+        self.inst_cache.clear();
+        #[cfg(feature = "log_coverage")]
+        self.coverage_entry_edges.clear();
+        self.transaction_coverage.clear();
+        #[cfg(feature = "retc")]
+        self.state.ret_comp_stack.clear();
 
-                match instruction.code() {
-                    Code::Vmlaunch | Code::Vmresume => Self::FarReturn,
-                    _ => Self::FarCall,
-                }
-            }
-            FlowControl::IndirectCall => {
-                if instruction.is_call_far_indirect() {
-                    Self::FarCall
-                } else {
-                    Self::CallIndirect
-                }
-            }
-            FlowControl::XbeginXabortXend => {
-                // todo: support transactions
-                Self::Other
-            }
+        DecoderScratch {
+            proceed_inst_cache: self.proceed_inst_cache,
+            #[cfg(feature = "cache")]
+            inst_cache: self.inst_cache,
+            #[cfg(feature = "log_coverage")]
+            coverage_entry_edges: self.coverage_entry_edges,
+            transaction_coverage: self.transaction_coverage,
+            #[cfg(feature = "retc")]
+            ret_comp_stack: self.state.ret_comp_stack,
         }
     }
-}
-
-#[cfg(test)]
-mod test {
-    use crate::coverage_decoder::InstructionClass;
-    use iced_x86::{Code, Instruction, Register};
-    use std::mem;
 
-    #[test]
-    fn from_works() {
-        for i in Code::Add_rm8_r8 as u16..=Code::VEX_Vsm3rnds2_xmm_xmm_xmmm128_imm8 as u16 {
-            let code: Code = unsafe { mem::transmute(i) };
+    /// Image containing `ip`, using the same lookup used while decoding.
+    pub fn resolve_image(&self, ip: u64) -> Option<&PtImage> {
+        self.state.resolve_image(&self.builder.images, ip)
+    }
 
-            // Ignore IA-64 leftovers
-            if matches!(
-                code,
-                Code::Jmpe_disp16 | Code::Jmpe_disp32 | Code::Jmpe_rm16 | Code::Jmpe_rm32
-            ) {
-                continue;
-            }
+    /// Transaction state of the last decoded MODE.TSX packet.
+    ///
+    /// Coverage recorded while [`TransactionState::Begin`] might belong to code whose effects are
+    /// later rolled back by an abort.
+    pub const fn transaction_state(&self) -> TransactionState {
+        self.state.mode_tsx.transaction_state()
+    }
 
-            // Ignore not Intel stuff (AMD SVM, Cyryx, ...)
-            if matches!(
-                code,
-                Code::Vmrunw
-                    | Code::Vmrund
-                    | Code::Vmrunq
-                    | Code::Vmmcall
-                    | Code::Skinit
-                    | Code::Vmgexit
-                    | Code::Smint
-                    | Code::Dmint
-                    | Code::Rdm
-                    | Code::Smint_0F7E
-                    | Code::Altinst
-                    | Code::Vmgexit_F2
-            ) {
-                continue;
-            }
+    /// Whether a MODE.Exec packet was decoded, otherwise the code is walked using
+    /// [`PtCoverageDecoderBuilder::initial_addressing_mode`].
+    pub const fn mode_exec_seen(&self) -> bool {
+        self.state.mode_exec_seen
+    }
 
-            // Ignore mvex
-            if matches!(
-                code,
-                Code::VEX_KNC_Jkzd_kr_rel8_64
-                    | Code::VEX_KNC_Jknzd_kr_rel8_64
-                    | Code::VEX_KNC_Jkzd_kr_rel32_64
-                    | Code::VEX_KNC_Jknzd_kr_rel32_64
-            ) {
-                continue;
-            }
+    /// Position in the trace where the flow starts, i.e. following the PSBEND of the first PSB+.
+    ///
+    /// None until the first PSB+ is decoded. The position is relative to the trace passed to the
+    /// decoding call that decoded that PSB+.
+    pub const fn flow_start_position(&self) -> Option<usize> {
+        self.state.flow_start_position
+    }
 
-            // todo: SMM stuff
-            if matches!(code, Code::Rsm) {
-                continue;
-            }
+    /// Value of the last decoded TSC packet, usually the one in PSB+, None if no TSC was decoded
+    /// yet.
+    ///
+    /// It anchors the timing of the packets that follow it.
+    #[cfg(feature = "tsc")]
+    pub const fn current_tsc(&self) -> Option<u64> {
+        self.state.tsc
+    }
 
-            //todo: TDX stuff
-            if matches!(code, Code::Tdcall | Code::Seamret | Code::Seamcall) {
-                continue;
-            }
+    /// Core:bus ratio of the last decoded CBR packet, None if no CBR was decoded yet.
+    ///
+    /// Use [`Self::coverage_with_events`] to know where the ratio changed in the trace.
+    #[cfg(feature = "cbr")]
+    pub const fn current_cbr(&self) -> Option<u8> {
+        self.state.cbr
+    }
 
-            let mut instruction = Instruction::new();
-            instruction.set_code(code);
-            assert_eq!(
-                InstructionClass::from(&instruction),
-                class_from_instruction_raw(&instruction),
-                "{i} - {code:?}"
-            );
+    /// VMCS pointer of the last decoded VMCS packet, i.e. the active guest when tracing VMX
+    /// non-root operation. None if no VMCS was decoded yet.
+    pub const fn current_vmcs(&self) -> Option<u64> {
+        match &self.state.vmcs {
+            Some(vmcs) => Some(vmcs.vmcs_pointer()),
+            None => None,
         }
     }
 
-    const fn class_from_instruction_raw(instruction: &Instruction) -> InstructionClass {
-        use Code::*;
+    /// Decode the whole trace, coverage is computed only if `iteration_state` has a map.
+    fn decode<CE>(
+        &mut self,
+        mut iteration_state: CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError>
+    where
+        CE: CoverageEntry,
+    {
+        self.trace_len = iteration_state.packet_decoder.trace_len();
+        let ret = loop {
+            match self.decode_step(&mut iteration_state) {
+                Ok(()) => continue,
+                Err(PtDecoderError::Eof) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        let position = iteration_state.packet_decoder.last_packet_range().end;
+        self.trace_position = match ret {
+            // The trailing padding is skipped, unless stopped by decode_until_offset
+            Ok(()) if position < iteration_state.end => self.trace_len,
+            _ => position,
+        };
+        ret
+    }
+
+    fn decode_step<CE: CoverageEntry>(
+        &mut self,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError> {
+        match self.proceed_with_trace(iteration_state) {
+            Err(PtDecoderError::MissingImage { .. }) if self.builder.skip_missing_images => {
+                self.skip_to_resync(iteration_state)
+            }
+            ret => ret,
+        }
+    }
+
+    /// Continue decoding using PT trace.
+    /// Can consume one or more PT packets.
+    fn proceed_with_trace<CE: CoverageEntry>(
+        &mut self,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError> {
+        if iteration_state.packet_decoder.last_packet_range().end >= iteration_state.end {
+            return Err(PtDecoderError::Eof);
+        }
+        let packet = iteration_state.packet_decoder.next_packet()?;
+
+        if self.is_kernel_ip(self.state.ip) {
+            return self.proceed_in_kernel(packet, iteration_state);
+        }
+
+        match packet {
+            PtPacket::TntShort(tnt_s) => {
+                self.proceed_inst_tnt(tnt_s.into_iter().into(), iteration_state)?
+            }
+            PtPacket::TntLong(tnt_l) => {
+                self.proceed_inst_tnt(tnt_l.into_iter().into(), iteration_state)?
+            }
+            PtPacket::Tip(tip) => self.proceed_inst_tip(tip, iteration_state)?,
+            PtPacket::TipPge(tip_pge) => self.handle_tip_pge(tip_pge)?,
+            PtPacket::TipPgd(tip_pgd) => self.handle_tip_pgd(tip_pgd)?,
+            PtPacket::Fup(fup) => self.handle_fup(fup, iteration_state)?,
+            PtPacket::Pip(pip) => self.handle_pip(pip, iteration_state)?,
+            PtPacket::ModeExec(mode_exec) => self.handle_mode_exec(mode_exec, iteration_state)?,
+            PtPacket::ModeTsx(mode_tsx) => self.handle_mode_tsx(mode_tsx, iteration_state)?,
+            PtPacket::TraceStop(..) => {} // todo
+            PtPacket::Vmcs(vmcs) => self.handle_vmcs(vmcs)?,
+            #[cfg(feature = "cbr")]
+            PtPacket::Cbr(cbr) => self.handle_cbr(cbr, iteration_state),
+            #[cfg(feature = "tsc")]
+            PtPacket::Tsc(tsc) => self.state.tsc = Some(tsc.tsc()),
+            PtPacket::Trig(trig) => handle_trig(trig, &self.builder, iteration_state),
+            #[cfg(feature = "event")]
+            PtPacket::Cfe(cfe) => self.handle_cfe(cfe, iteration_state)?,
+            PtPacket::Ovf(..) => self.handle_ovf(iteration_state)?,
+            PtPacket::Psb(..) => self.handle_psb(iteration_state)?,
+            PtPacket::PsbEnd(psb_end) => {
+                return Err(PtDecoderError::InvalidPacketSequence {
+                    packets: vec![PtPacket::PsbEnd(psb_end)],
+                });
+            }
+            _ => {} // Ignore other packets
+        };
+        Ok(())
+    }
+
+    fn handle_psb<CE: CoverageEntry>(
+        &mut self,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError> {
+        if let Some(Callback(check)) = &self.builder.integrity_check
+            && let Some(segment) = iteration_state.packet_decoder.last_sync_segment()
+            && !check.lock().unwrap_or_else(PoisonError::into_inner)(segment)
+        {
+            return Err(PtDecoderError::IntegrityCheckFailed);
+        }
+        decode_psbplus(iteration_state, &self.builder, &mut self.state)
+    }
+
+    /// Drop the packets up to the next TIP.PGE or PSB, where the IP is known again.
+    fn skip_to_resync<CE: CoverageEntry>(
+        &mut self,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError> {
+        self.state.packet_en = false;
+        self.state.vm_entry_pending = false;
+        loop {
+            match iteration_state.packet_decoder.peek_packet()? {
+                PtPacket::TipPge(..) | PtPacket::Psb(..) => return Ok(()),
+                // Keep the last IP up to date, the next IPs might be compressed against it
+                PtPacket::Tip(tip) | PtPacket::TipPgd(tip) | PtPacket::Fup(tip) => {
+                    self.update_tip_last_ip(&tip)?;
+                }
+                #[cfg(feature = "cbr")]
+                PtPacket::Cbr(cbr) => self.handle_cbr(cbr, iteration_state),
+                _ => {}
+            }
+            iteration_state.packet_decoder.next_packet()?;
+        }
+    }
+
+    /// Same as `proceed_with_trace`, but without walking the code, used while executing kernel
+    /// code and [`PtCoverageDecoderBuilder::userspace_only`] is set.
+    fn proceed_in_kernel<CE: CoverageEntry>(
+        &mut self,
+        packet: PtPacket,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError> {
+        match packet {
+            // A TIP with suppressed IP is ignored by the catch-all arm
+            PtPacket::Tip(tip) if self.update_tip_last_ip(&tip)? => {
+                self.state.ip = self.state.tip_last_ip;
+            }
+            PtPacket::TipPge(tip_pge) => self.handle_tip_pge(tip_pge)?,
+            PtPacket::TipPgd(tip_pgd) => {
+                self.state.packet_en = false;
+                self.update_tip_last_ip(&tip_pgd)?;
+            }
+            PtPacket::Fup(fup) => {
+                self.update_tip_last_ip(&fup)?;
+            }
+            PtPacket::Pip(pip) => self.handle_async_pip(pip, iteration_state),
+            PtPacket::Vmcs(vmcs) => self.state.vmcs = Some(vmcs),
+            PtPacket::ModeExec(mode_exec) => self.state.update_mode_exec(mode_exec),
+            PtPacket::ModeTsx(mode_tsx) => self.state.mode_tsx = mode_tsx,
+            #[cfg(feature = "cbr")]
+            PtPacket::Cbr(cbr) => self.handle_cbr(cbr, iteration_state),
+            #[cfg(feature = "tsc")]
+            PtPacket::Tsc(tsc) => self.state.tsc = Some(tsc.tsc()),
+            PtPacket::Trig(trig) => handle_trig(trig, &self.builder, iteration_state),
+            PtPacket::Ovf(..) => self.handle_ovf(iteration_state)?,
+            PtPacket::Psb(..) => self.handle_psb(iteration_state)?,
+            PtPacket::PsbEnd(psb_end) => {
+                return Err(PtDecoderError::InvalidPacketSequence {
+                    packets: vec![PtPacket::PsbEnd(psb_end)],
+                });
+            }
+            _ => {} // TNTs and other packets are ignored
+        }
+        Ok(())
+    }
+
+    const fn is_kernel_ip(&self, ip: u64) -> bool {
+        self.builder.userspace_only && ip >= self.builder.kernel_address_start
+    }
+
+    /// Always true when no IP filter range is set
+    fn in_filter_en_ranges(&self, ip: u64) -> bool {
+        let ranges = &self.builder.filter_en_ranges;
+        ranges.is_empty()
+            || ranges
+                .iter()
+                .any(|&(start, end)| (start..end).contains(&ip))
+    }
+
+    /// Check that tracing is enabled (`enabled == true`) inside the IP filter ranges or disabled
+    /// outside them, see [`PtCoverageDecoderBuilder::check_filter_en_ranges`]
+    fn check_filter_en_ip(&self, ip: u64, enabled: bool) -> Result<(), PtDecoderError> {
+        if self.builder.filter_en_ranges.is_empty() || self.in_filter_en_ranges(ip) == enabled {
+            return Ok(());
+        }
+        if self.builder.check_filter_en_ranges {
+            return Err(PtDecoderError::FilterEnRangesMismatch(ip));
+        }
+        #[cfg(feature = "log_packets")]
+        log::warn!(
+            "Tracing {} at 0x{ip:x}, {} the IP filter ranges",
+            if enabled { "enabled" } else { "disabled" },
+            if enabled { "outside" } else { "inside" }
+        );
+        Ok(())
+    }
+
+    fn handle_ovf<CE: CoverageEntry>(
+        &mut self,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError> {
+        #[cfg(feature = "retc")]
+        self.state.ret_comp_stack.clear();
+
+        // state.packet_en might have changed during the overflow
+        if let PtPacket::Fup(fup) = iteration_state.packet_decoder.peek_packet()? {
+            iteration_state.packet_decoder.next_packet()?;
+            self.state.packet_en = true;
+            self.handle_fup_after_ovf(fup)
+        } else {
+            self.state.packet_en = false;
+            Ok(())
+        }
+    }
+
+    fn handle_mode_tsx<CE: CoverageEntry>(
+        &mut self,
+        mode_tsx: ModeTsx,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError> {
+        // todo: double check this function
+        let fup = if self.state.packet_en {
+            let fup = match iteration_state.packet_decoder.next_packet()? {
+                PtPacket::Fup(fup) => fup,
+                p => {
+                    return Err(PtDecoderError::InvalidPacketSequence {
+                        packets: vec![PtPacket::ModeTsx(mode_tsx), p],
+                    });
+                }
+            };
+            self.handle_standalone_fup(&fup)?;
+            Some(fup)
+        } else {
+            None
+        };
+
+        self.state.mode_tsx = mode_tsx.clone();
+        match mode_tsx.transaction_state() {
+            TransactionState::Begin => {}
+            TransactionState::Commit => {
+                // The map might differ from the one of the call that started the transaction
+                let mut transaction_coverage = mem::take(&mut self.transaction_coverage);
+                for (map, edge) in transaction_coverage.drain(..) {
+                    self.record_edge(map, edge, iteration_state);
+                }
+                self.transaction_coverage = transaction_coverage;
+            }
+            TransactionState::Abort => self.transaction_coverage.clear(),
+        }
+
+        if let Some(fup) = fup
+            && mode_tsx.transaction_state() == TransactionState::Abort
+        {
+            // The FUP carries the IP where the transaction aborted, the TIP the abort handler
+            match iteration_state.packet_decoder.next_packet()? {
+                PtPacket::Tip(tip) => self.handle_async_tip(tip, iteration_state)?,
+                PtPacket::TipPge(tip_pge) => self.handle_tip_pge(tip_pge)?,
+                PtPacket::TipPgd(tip_pgd) => self.handle_async_tip_pgd(tip_pgd)?,
+                p => {
+                    return Err(PtDecoderError::InvalidPacketSequence {
+                        packets: vec![PtPacket::ModeTsx(mode_tsx), PtPacket::Fup(fup), p],
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Update the last IP using the IP carried by `tip`.
+    ///
+    /// Returns `Ok(false)` if the packet IP is suppressed, in this case the last IP is left
+    /// untouched and can be reused. Use [`Self::required_tip_ip`] when the packet must carry an IP.
+    fn update_tip_last_ip(&mut self, tip: &Tip) -> Result<bool, PtDecoderError> {
+        if !tip.ip(&mut self.state.tip_last_ip) {
+            return Ok(false);
+        }
+
+        if self.builder.check_canonical_ip && !is_canonical_48(self.state.tip_last_ip) {
+            return Err(PtDecoderError::NonCanonicalIp(self.state.tip_last_ip));
+        }
+        Ok(true)
+    }
+
+    /// Update the last IP using the IP carried by `tip` and return it.
+    ///
+    /// Returns [`PtDecoderError::IpSuppressed`] if the packet IP is suppressed.
+    fn required_tip_ip(&mut self, tip: &Tip) -> Result<u64, PtDecoderError> {
+        if tip.is_ip_suppressed() {
+            return Err(PtDecoderError::IpSuppressed);
+        }
+        self.update_tip_last_ip(tip)?;
+        Ok(self.state.tip_last_ip)
+    }
+
+    fn handle_fup_after_ovf(&mut self, fup: Fup) -> Result<(), PtDecoderError> {
+        self.state.ip = self.required_tip_ip(&fup)?;
+        Ok(())
+    }
+
+    fn handle_standalone_fup(&mut self, fup: &Fup) -> Result<(), PtDecoderError> {
+        let ip = self.required_tip_ip(fup)?;
+
+        match self.proceed_inst_until(Some(ip))? {
+            ProceedInstStopReason::UntilIpReached => Ok(()),
+            _ => Err(PtDecoderError::IncoherentImage),
+        }
+    }
+
+    fn handle_mode_exec<CE: CoverageEntry>(
+        &mut self,
+        mode_exec: ModeExec,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError> {
+        match iteration_state.packet_decoder.next_packet()? {
+            PtPacket::Tip(tip) => self.proceed_inst_tip(tip, iteration_state)?,
+            PtPacket::TipPge(tip_pge) => {
+                // No code is walked up to the TIP.PGE, the new mode applies from its target
+                self.state.update_mode_exec(mode_exec);
+                return self.handle_tip_pge(tip_pge);
+            }
+            PtPacket::Fup(fup) => self.handle_standalone_fup(&fup)?,
+            p => {
+                return Err(PtDecoderError::InvalidPacketSequence {
+                    packets: vec![PtPacket::ModeExec(mode_exec), p],
+                });
+            }
+        }
+        self.state.update_mode_exec(mode_exec);
+
+        Ok(())
+    }
+
+    fn handle_async_pip<CE: CoverageEntry>(
+        &mut self,
+        pip: Pip,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) {
+        // todo: The purpose of the PIP is to indicate to the decoder which application is running,
+        // so that it can apply the proper binaries to the linear addresses that are being traced.
+        // add decoder cr3 filtering
+
+        if let Some(event) = self.state.update_pip(pip, &self.builder) {
+            iteration_state.event(event);
+        }
+    }
+
+    fn handle_pip<CE: CoverageEntry>(
+        &mut self,
+        pip: Pip,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError> {
+        use ProceedInstStopReason::*;
+
+        if self.state.packet_en && !self.state.vm_entry_pending {
+            match self.proceed_inst_until(None)? {
+                MovCr3 | FarIndirect { .. } => {}
+                VmEntry => self.state.vm_entry_pending = true,
+                CondBranch { .. } | Indirect { .. } | OutsideFilterEnRanges | Return => {
+                    return Err(PtDecoderError::IncoherentImage);
+                }
+                UntilIpReached => unreachable!("until parameter is set to None"),
+            }
+        }
+
+        self.handle_async_pip(pip, iteration_state);
+        Ok(())
+    }
+
+    /// A MOV CR3 was reached while walking the code for a TNT or TIP: the PIP it generated must
+    /// be the next packet, apply it before resuming the walk.
+    fn handle_mov_cr3<CE: CoverageEntry>(
+        &mut self,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError> {
+        match iteration_state.packet_decoder.next_packet()? {
+            PtPacket::Pip(pip) => {
+                self.handle_async_pip(pip, iteration_state);
+                Ok(())
+            }
+            p => Err(PtDecoderError::InvalidPacketSequence { packets: vec![p] }),
+        }
+    }
+
+    /// CBR packets are not bound to any instruction, the ratio applies from here on.
+    #[cfg(feature = "cbr")]
+    fn handle_cbr<CE: CoverageEntry>(
+        &mut self,
+        cbr: Cbr,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) {
+        if let Some(event) = self.state.update_cbr(cbr) {
+            iteration_state.event(event);
+        }
+    }
+
+    /// A standalone VMCS packet is generated by VMLAUNCH/VMRESUME, before the TIP to the guest.
+    fn handle_vmcs(&mut self, vmcs: Vmcs) -> Result<(), PtDecoderError> {
+        use ProceedInstStopReason::*;
+
+        if self.state.packet_en && !self.state.vm_entry_pending {
+            match self.proceed_inst_until(None)? {
+                VmEntry => self.state.vm_entry_pending = true,
+                CondBranch { .. }
+                | FarIndirect { .. }
+                | Indirect { .. }
+                | MovCr3
+                | OutsideFilterEnRanges
+                | Return => {
+                    return Err(PtDecoderError::IncoherentImage);
+                }
+                UntilIpReached => unreachable!("until parameter is set to None"),
+            }
+        }
+
+        self.state.vmcs = Some(vmcs);
+        Ok(())
+    }
+
+    /// Asynchronous events (interrupts, exceptions, VM exits, ...) generate a FUP with the source
+    /// IP, followed by a TIP with the target IP. Far calls reached while walking the code are
+    /// handled by `proceed_inst_tip` instead, since they only generate a TIP.
+    fn handle_fup<CE: CoverageEntry>(
+        &mut self,
+        fup: Fup,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError> {
+        self.handle_standalone_fup(&fup)?;
+        loop {
+            let packet = iteration_state.packet_decoder.next_packet()?;
+            match packet {
+                PtPacket::Pip(pip) => self.handle_async_pip(pip, iteration_state),
+                PtPacket::Vmcs(vmcs) => self.state.vmcs = Some(vmcs),
+                PtPacket::ModeExec(mode_exec) => self.state.update_mode_exec(mode_exec),
+                PtPacket::Tip(tip) => {
+                    // Interrupts, exceptions and VM exits are handled by the kernel
+                    self.state.ring = Some(Ring::Kernel);
+                    break self.handle_async_tip(tip, iteration_state)?;
+                }
+                PtPacket::TipPgd(tip_pgd) => break self.handle_async_tip_pgd(tip_pgd)?,
+                p => {
+                    // todo handle overflow packet here (and in other InvalidPacketSequence?)
+                    return Err(PtDecoderError::InvalidPacketSequence {
+                        packets: vec![PtPacket::Fup(fup), p],
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Bind the event to the IP of the FUP following the CFE, if any. When the event redirects the
+    /// control flow, the TIP after the FUP is recorded as an edge from the bound IP.
+    #[cfg(feature = "event")]
+    fn handle_cfe<CE: CoverageEntry>(
+        &mut self,
+        cfe: Cfe,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError> {
+        let mut ip = None;
+        if cfe.ip() {
+            let fup = loop {
+                match iteration_state.packet_decoder.next_packet()? {
+                    PtPacket::Evd(..) => {}
+                    PtPacket::Fup(fup) => break fup,
+                    p => {
+                        return Err(PtDecoderError::InvalidPacketSequence {
+                            packets: vec![PtPacket::Cfe(cfe), p],
+                        });
+                    }
+                }
+            };
+            self.handle_standalone_fup(&fup)?;
+            ip = Some(self.state.ip);
+
+            match iteration_state.packet_decoder.peek_packet()? {
+                PtPacket::Tip(tip) => {
+                    iteration_state.packet_decoder.next_packet()?;
+                    self.handle_async_tip(tip, iteration_state)?;
+                }
+                PtPacket::TipPgd(tip_pgd) => {
+                    iteration_state.packet_decoder.next_packet()?;
+                    self.handle_async_tip_pgd(tip_pgd)?;
+                }
+                _ => {}
+            }
+        }
+
+        iteration_state.event(PtDecoderEvent::ControlFlowEvent {
+            cfe_type: cfe.cfe_type(),
+            vector: cfe.vector(),
+            ip,
+        });
+        Ok(())
+    }
+
+    fn handle_async_tip<CE: CoverageEntry>(
+        &mut self,
+        tip: Tip,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError> {
+        if self.update_tip_last_ip(&tip)? {
+            self.add_coverage_entry(
+                self.state.tip_last_ip,
+                InstructionClass::Async,
+                iteration_state,
+            );
+            self.state.ip = self.state.tip_last_ip;
+            Ok(())
+        } else {
+            // we jumped somewhere but who knows where?
+            Err(PtDecoderError::InvalidPacketSequence {
+                packets: vec![PtPacket::Tip(tip)],
+            })
+        }
+    }
+
+    fn handle_async_tip_pgd(&mut self, tip_pgd: TipPgd) -> Result<(), PtDecoderError> {
+        self.state.packet_en = false;
+        if self.update_tip_last_ip(&tip_pgd)? {
+            self.state.ip = self.state.tip_last_ip;
+        }
+        Ok(())
+    }
+
+    fn handle_tip_pgd(&mut self, tip_pgd: TipPgd) -> Result<(), PtDecoderError> {
+        use ProceedInstStopReason::*;
+
+        let ret = if mem::take(&mut self.state.vm_entry_pending) {
+            // Tracing disabled by the VM entry itself
+            self.update_tip_last_ip(&tip_pgd).map(|_| ())
+        } else if self.update_tip_last_ip(&tip_pgd)? {
+            self.check_filter_en_ip(self.state.tip_last_ip, false)?;
+            match self.proceed_inst_until(Some(self.state.tip_last_ip))? {
+                CondBranch { .. }
+                | Indirect { .. }
+                | FarIndirect { .. }
+                | OutsideFilterEnRanges
+                | UntilIpReached
+                | Return
+                | VmEntry => Ok(()),
+                MovCr3 => Err(PtDecoderError::IncoherentImage),
+            }
+        } else {
+            // might be caused by:
+            // - trace stopped manually or operational error probably there is no way to get the
+            //   precise ip where tracing actually stopped... Let's ignore this case.
+            // - a conditional branch (replaces TNT)
+            // - Change of CPL/CR3
+            match self.proceed_inst_until(None)? {
+                CondBranch { .. }
+                | Indirect { .. }
+                | FarIndirect { .. }
+                | MovCr3
+                | OutsideFilterEnRanges
+                | Return
+                | VmEntry => Ok(()),
+                UntilIpReached => unreachable!("until parameter is set to None"),
+            }
+        };
+
+        self.state.packet_en = false;
+        ret
+    }
+
+    fn handle_tip_pge(&mut self, tip_pge: TipPge) -> Result<(), PtDecoderError> {
+        self.state.ip = self.required_tip_ip(&tip_pge)?;
+        self.check_filter_en_ip(self.state.ip, true)?;
+        #[cfg(feature = "log_packets")]
+        if !self.state.mode_exec_seen {
+            log::warn!(
+                "Tracing enabled at 0x{:x} before any MODE.Exec, assuming {}",
+                self.state.ip,
+                self.state.mode_exec.addressing_mode()
+            );
+        }
+        self.state.packet_en = true;
+        Ok(())
+    }
+
+    fn proceed_inst_tip<CE: CoverageEntry>(
+        &mut self,
+        tip: Tip,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError> {
+        use ProceedInstStopReason::*;
+
+        let reason = loop {
+            let reason = if mem::take(&mut self.state.vm_entry_pending) {
+                VmEntry
+            } else {
+                self.proceed_inst_until(None)?
+            };
+            if reason != MovCr3 {
+                break reason;
+            }
+            self.handle_mov_cr3(iteration_state)?;
+        };
+        match reason {
+            Indirect { .. } | FarIndirect { .. } | Return | VmEntry => {
+                let ip = self.required_tip_ip(&tip)?;
+                self.add_coverage_entry(ip, reason.class(), iteration_state);
+                self.state.ip = ip;
+                Ok(())
+            }
+            CondBranch { .. } | MovCr3 | OutsideFilterEnRanges => {
+                Err(PtDecoderError::IncoherentImage)
+            }
+            UntilIpReached => unreachable!("until parameter is set to None"),
+        }
+    }
+
+    /// Proceed decoding the instructions until next decision point, considering the current PT
+    /// packet is a TNT
+    fn proceed_inst_tnt<CE: CoverageEntry>(
+        &mut self,
+        mut tnt_iter: TntIter,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) -> Result<(), PtDecoderError> {
+        use ProceedInstStopReason::*;
+        #[cfg(feature = "log_packets")]
+        log::trace!("TNT handling start");
+
+        while let Some(tnt) = tnt_iter.next() {
+            'inst: loop {
+                let reason = self.proceed_inst_until(None)?;
+                match reason {
+                    // TNT consumed at the current decision point
+                    CondBranch { to } => {
+                        if tnt {
+                            self.add_coverage_entry(
+                                to,
+                                InstructionClass::CondBranch,
+                                iteration_state,
+                            );
+                            self.state.ip = to;
+                            #[cfg(feature = "log_packets")]
+                            log::trace!("TNT taken to 0x{:x}", self.state.ip);
+                        } else {
+                            #[cfg(feature = "log_packets")]
+                            log::trace!("TNT not taken");
+                        }
+                        break 'inst;
+                    }
+                    #[cfg(feature = "retc")]
+                    Return => {
+                        // A not taken bit cannot compress a return
+                        if !tnt {
+                            return Err(PtDecoderError::IncoherentImage);
+                        }
+                        let Some(to) = self.state.ret_comp_stack.pop_back() else {
+                            return Err(PtDecoderError::EmptyReturnStack);
+                        };
+                        let to_masked = match self.state.mode_exec.addressing_mode() {
+                            AddressingMode::_16 => to & u16::MAX as u64,
+                            AddressingMode::_32 => to & u32::MAX as u64,
+                            AddressingMode::_64 => to,
+                        };
+                        self.add_coverage_entry(
+                            to_masked,
+                            InstructionClass::Return,
+                            iteration_state,
+                        );
+                        self.state.ip = to_masked;
+                        #[cfg(feature = "log_packets")]
+                        log::trace!("TNT taken (return compression) to 0x{:x}", self.state.ip);
+                        break 'inst;
+                    }
+
+                    // TNT NOT consumed at the current decision point, handle the decision point
+                    // and continue in the loop without consuming the TNT
+                    #[cfg_attr(feature = "retc", expect(unreachable_patterns))]
+                    Indirect { .. } | FarIndirect { .. } | Return | VmEntry => {
+                        // handle possible deferred tips
+                        let deferred = iteration_state.packet_decoder.next_packet()?;
+                        let tip = if let PtPacket::Tip(tip) = deferred {
+                            tip
+                        } else {
+                            return Err(PtDecoderError::InvalidPacketSequence {
+                                packets: vec![deferred],
+                            }); // todo add tnt here to the sequence
+                        };
+
+                        let ip = self.required_tip_ip(&tip)?;
+                        self.add_coverage_entry(ip, reason.class(), iteration_state);
+                        self.state.ip = ip;
+                        if self.is_kernel_ip(ip) {
+                            if tnt_iter.next().is_none() {
+                                return Ok(());
+                            }
+                            // The remaining bits belong to the kernel code, and possibly to the
+                            // userspace code it returns to: they cannot be told apart
+                            #[cfg(feature = "log_packets")]
+                            log::warn!("TNT bits left after entering the kernel, resyncing");
+                            return self.skip_to_resync(iteration_state);
+                        }
+                    }
+                    // TNT NOT consumed, apply the PIP generated by the MOV CR3
+                    MovCr3 => self.handle_mov_cr3(iteration_state)?,
+                    // Tracing would have been disabled by a TIP.PGD
+                    OutsideFilterEnRanges => return Err(PtDecoderError::IncoherentImage),
+                    UntilIpReached => unreachable!("until parameter is set to None"),
+                }
+            }
+        }
+
+        #[cfg(feature = "log_packets")]
+        log::trace!("TNT handling end");
+        Ok(())
+    }
+
+    fn proceed_inst_until(
+        &mut self,
+        until: Option<u64>,
+    ) -> Result<ProceedInstStopReason, PtDecoderError> {
+        use ProceedInstStopReason::*;
+
+        if !self.state.packet_en {
+            return Err(PtDecoderError::IncoherentState);
+        }
+
+        // Use cache (only if until is None and the walked instructions are not observed)
+        if until.is_none()
+            && self.builder.instruction_hook.is_none()
+            && self.instruction_ips.is_none()
+            && let Some(&(ip, reason)) = self
+                .proceed_inst_cache
+                .get(&(self.state.ip, self.state.cr3))
+        {
+            #[cfg(feature = "log_instructions")]
+            log::trace!(
+                "Cache hit: skipping disassembling from 0x{:x} to 0x{ip:x}",
+                self.state.ip
+            );
+            self.state.ip = ip;
+            self.state.update_ring(reason);
+            return Ok(reason);
+        }
+
+        let from = self.state.ip;
+        let mut inst_decoder: Option<iced_x86::Decoder> = None;
+        let mut direct_branches = 0;
+        let inst = loop {
+            if let Some(ip) = until
+                && self.state.ip == ip
+            {
+                return Ok(UntilIpReached);
+            }
+            if !self.in_filter_en_ranges(self.state.ip) {
+                return Ok(OutsideFilterEnRanges);
+            }
+
+            if let Some(ips) = &mut self.instruction_ips {
+                ips.push(self.state.ip);
+            }
+
+            let cached = match self.builder.instruction_hook {
+                None => self.cached_instruction(),
+                Some(..) => None,
+            };
+            let inst = match cached {
+                Some(inst) => inst,
+                None => {
+                    let decoder = match inst_decoder
+                        .take()
+                        .and_then(|d| self.state.reposition_inst_decoder(d))
+                    {
+                        Some(d) => d,
+                        None => {
+                            #[cfg(test)]
+                            {
+                                self.inst_decoder_builds += 1;
+                            }
+                            self.state.new_inst_decoder(
+                                &self.builder.images,
+                                self.builder.decoder_options,
+                            )?
+                        }
+                    };
+                    let decoder = inst_decoder.insert(decoder);
+
+                    let ins = decoder.decode();
+                    #[cfg(feature = "log_instructions")]
+                    log::trace!(
+                        "\tip: 0x{:x}: {:?} {:?} raw: {:x?}",
+                        decoder.ip() - ins.len() as u64,
+                        ins.code(),
+                        ins.op0_kind(),
+                        &self.builder.images[0].data()
+                            [decoder.position()..decoder.position() + ins.len()],
+                    );
+                    if ins.is_invalid() {
+                        return Err(PtDecoderError::MalformedInstruction);
+                    }
+                    if let Some(Callback(hook)) = &self.builder.instruction_hook {
+                        hook.lock().unwrap_or_else(PoisonError::into_inner)(&ins);
+                    }
+
+                    let inst = DecodedInstruction::from(&ins);
+                    #[cfg(feature = "cache")]
+                    self.inst_cache.insert(
+                        (
+                            self.state.ip,
+                            self.state.cr3,
+                            self.state.mode_exec.addressing_mode(),
+                        ),
+                        inst,
+                    );
+                    inst
+                }
+            };
+            // todo log call for retcomp
+
+            match next_ip(&inst) {
+                Ok(None) => self.state.ip = inst.next_ip,
+                Ok(Some(_)) if direct_branches == MAX_DIRECT_BRANCHES => {
+                    return Err(PtDecoderError::InfiniteLoop(self.state.ip));
+                }
+                Ok(Some(ip)) => {
+                    direct_branches += 1;
+                    #[cfg(feature = "retc")]
+                    self.state
+                        .push_ret(inst.next_ip, self.builder.ret_comp_stack_depth);
+                    #[cfg(all(feature = "retc", feature = "log_packets"))]
+                    log::trace!("Pushed on retc stack: 0x{:x}", inst.next_ip);
+                    self.state.ip = ip;
+                }
+                Err(()) => {
+                    self.state.ip = inst.next_ip;
+                    break inst;
+                }
+            }
+        };
+
+        let ret = match inst.class {
+            InstructionClass::CondBranch => CondBranch {
+                to: inst.branch_target,
+            },
+            InstructionClass::Return => Return,
+            InstructionClass::JumpIndirect | InstructionClass::CallIndirect => {
+                Indirect { class: inst.class }
+            }
+            InstructionClass::FarCall | InstructionClass::FarReturn | InstructionClass::FarJump => {
+                FarIndirect {
+                    to_ring: inst.to_ring,
+                    class: inst.class,
+                }
+            }
+            InstructionClass::MovCr3 => MovCr3,
+            InstructionClass::VmEntry => VmEntry,
+            InstructionClass::JumpDirect
+            | InstructionClass::CallDirect
+            | InstructionClass::Other => unreachable!("These instructions do not need traces"),
+            InstructionClass::Async => unreachable!("Not an instruction class"),
+        };
+
+        self.proceed_inst_cache
+            .insert((from, self.state.cr3), (self.state.ip, ret));
+        self.state.update_ring(ret);
+        Ok(ret)
+    }
+
+    #[cfg(feature = "cache")]
+    fn cached_instruction(&self) -> Option<DecodedInstruction> {
+        self.inst_cache
+            .get(&(
+                self.state.ip,
+                self.state.cr3,
+                self.state.mode_exec.addressing_mode(),
+            ))
+            .copied()
+    }
+
+    #[cfg(not(feature = "cache"))]
+    const fn cached_instruction(&self) -> Option<DecodedInstruction> {
+        None
+    }
+
+    /// IP used to compute the coverage map entries, see
+    /// [`PtCoverageDecoderBuilder::relative_coverage`]
+    fn coverage_ip(&self, ip: u64) -> u64 {
+        if !self.builder.relative_coverage {
+            return ip;
+        }
+
+        let images = &self.builder.images;
+        match self.state.resolve_image_index(images, ip) {
+            // Image index in the upper bits, the offset in an image fits in the lower 48 bits
+            Some(index) => {
+                ((index as u64 + 1) << 48) | (ip - images[index].virtual_address_start())
+            }
+            None => ip,
+        }
+    }
+
+    fn add_coverage_entry<CE: CoverageEntry>(
+        &mut self,
+        to_ip: u64,
+        class: InstructionClass,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) {
+        // With per-image maps, edges are recorded in the map of the target image
+        let map = if iteration_state.image_coverages.is_some() {
+            match self.state.resolve_image_index(&self.builder.images, to_ip) {
+                Some(index) => Some(index),
+                None => return,
+            }
+        } else {
+            None
+        };
+
+        if !self.state.save_coverage
+            || self.is_kernel_ip(to_ip)
+            || self
+                .builder
+                .masked_ranges
+                .iter()
+                .any(|&(start, end)| (start..end).contains(&to_ip))
+            || (self.builder.ring_filter.is_some()
+                && self.state.ring.is_some()
+                && self.builder.ring_filter != self.state.ring)
+        {
+            return;
+        }
+
+        let mut edge = Edge {
+            from: self.state.ip,
+            to: to_ip,
+            from_image: None,
+            to_image: None,
+            class,
+        };
+        if self.builder.edge_image_indices && iteration_state.edges.is_some() {
+            let images = &self.builder.images;
+            let index = |ip| self.state.resolve_image_index(images, ip);
+            edge.from_image = index(edge.from);
+            edge.to_image = index(edge.to);
+        }
+
+        if self.builder.exclude_aborted_transactions
+            && self.state.mode_tsx.transaction_state() == TransactionState::Begin
+        {
+            self.transaction_coverage.push((map, edge));
+        } else {
+            self.record_edge(map, edge, iteration_state);
+        }
+    }
+
+    /// Record `edge` in the sinks and in the `map` coverage map, if any
+    fn record_edge<CE: CoverageEntry>(
+        &mut self,
+        map: Option<usize>,
+        edge: Edge,
+        iteration_state: &mut CovDecIterationState<CE>,
+    ) {
+        let cov_entry = iteration_state.map(map).and_then(|coverage| {
+            coverage_entry(
+                self.coverage_ip(edge.from),
+                self.coverage_ip(edge.to),
+                coverage.len(),
+            )
+        });
+
+        #[cfg(feature = "log_coverage")]
+        if let Some(cov_entry) = cov_entry
+            && let Some((from, to)) = self
+                .coverage_entry_edges
+                .insert((map, cov_entry), (edge.from, edge.to))
+            && (from, to) != (edge.from, edge.to)
+        {
+            log::debug!(
+                "Coverage entry {cov_entry} collision: 0x{from:x} -> 0x{to:x} and 0x{:x} -> 0x{:x}",
+                edge.from,
+                edge.to
+            );
+        }
+
+        iteration_state.hit(map, cov_entry, edge);
+    }
+}
+
+/// Compute the coverage of `pt_trace` in a new map of `map_size` entries.
+///
+/// Shorthand for building a [`PtCoverageDecoder`] with default settings and decoding the whole
+/// trace with it.
+pub fn coverage(
+    pt_trace: &[u8],
+    images: &[PtImage],
+    map_size: usize,
+) -> Result<Vec<u32>, PtDecoderError> {
+    let mut coverage = vec![0; map_size];
+    PtCoverageDecoderBuilder::new()
+        .images(images.to_vec())
+        .build()?
+        .coverage(pt_trace, &mut coverage)?;
+    Ok(coverage)
+}
+
+/// Fingerprint of a coverage map, e.g. to check whether an execution changed the coverage.
+///
+/// The hash depends on the value and on the position of each cell, and is stable across runs and
+/// platforms.
+pub fn coverage_hash<CE>(map: &[CE]) -> u64
+where
+    CE: Copy + Into<u64>,
+{
+    map.iter().fold(map.len() as u64, |hash, &cell| {
+        fmix64(hash.rotate_left(5) ^ cell.into())
+    })
+}
+
+/// Walk the code of `image` starting at `ip`, following direct jumps and calls, until the first
+/// instruction that needs a trace to proceed.
+///
+/// Returns the IP and the class of that instruction. No PT packet is decoded.
+/// [`PtDecoderError::InfiniteLoop`] is returned if the walk loops on direct branches.
+pub fn classify_from(
+    image: &PtImage,
+    ip: u64,
+    bitness: AddressingMode,
+) -> Result<(u64, InstructionClass), PtDecoderError> {
+    let images = slice::from_ref(image);
+    let mut state = ExecutionState::new(bitness);
+    state.ip = ip;
+
+    let mut inst_decoder: Option<iced_x86::Decoder> = None;
+    let mut direct_branches = 0;
+    loop {
+        let decoder = match inst_decoder
+            .take()
+            .and_then(|d| state.reposition_inst_decoder(d))
+        {
+            Some(d) => d,
+            None => state.new_inst_decoder(images, iced_x86::DecoderOptions::NONE)?,
+        };
+        let decoder = inst_decoder.insert(decoder);
+
+        let ins = decoder.decode();
+        if ins.is_invalid() {
+            return Err(PtDecoderError::MalformedInstruction);
+        }
+
+        let inst = DecodedInstruction::from(&ins);
+        state.ip = match next_ip(&inst) {
+            Ok(None) => inst.next_ip,
+            Ok(Some(_)) if direct_branches == MAX_DIRECT_BRANCHES => {
+                return Err(PtDecoderError::InfiniteLoop(ins.ip()));
+            }
+            Ok(Some(ip)) => {
+                direct_branches += 1;
+                ip
+            }
+            Err(()) => return Ok((ins.ip(), inst.class)),
+        };
+    }
+}
+
+/// Branch sites of an image, found by [`static_edge_count`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StaticEdgeCount {
+    /// Conditional branches, each one is an edge when taken
+    pub cond_branches: usize,
+    /// Indirect jumps and calls, returns, far transfers and VM entries, whose targets come from
+    /// the trace
+    pub indirect_branches: usize,
+}
+
+impl StaticEdgeCount {
+    /// Estimate of the edges that can be recorded in the image.
+    ///
+    /// The targets of indirect branches cannot be enumerated statically, each site is counted as
+    /// a single edge: the estimate is a lower bound.
+    pub const fn total(&self) -> usize {
+        self.cond_branches + self.indirect_branches
+    }
+
+    /// Ratio of `covered_edges`, the distinct edges recorded in the image, to [`Self::total`].
+    ///
+    /// Capped at 1.0, since indirect branches can have more targets than estimated. Returns 0.0
+    /// for an image without branches.
+    pub fn ratio(&self, covered_edges: usize) -> f64 {
+        if self.total() == 0 {
+            0.0
+        } else {
+            (covered_edges as f64 / self.total() as f64).min(1.0)
+        }
+    }
+}
+
+/// Count the branch sites of `image`, decoding all its instructions in sequence.
+///
+/// The linear sweep does not tell code from data embedded in it, bytes that decode to branches
+/// are counted too. Invalid instructions are skipped.
+pub fn static_edge_count(image: &PtImage, bitness: AddressingMode) -> StaticEdgeCount {
+    let mut count = StaticEdgeCount::default();
+    let decoder = iced_x86::Decoder::with_ip(
+        bitness.into(),
+        image.data(),
+        image.virtual_address_start(),
+        iced_x86::DecoderOptions::NONE,
+    );
+
+    for ins in decoder {
+        match InstructionClass::from(&ins) {
+            InstructionClass::CondBranch => count.cond_branches += 1,
+            InstructionClass::JumpIndirect
+            | InstructionClass::CallIndirect
+            | InstructionClass::Return
+            | InstructionClass::FarCall
+            | InstructionClass::FarJump
+            | InstructionClass::FarReturn
+            | InstructionClass::VmEntry => count.indirect_branches += 1,
+            InstructionClass::Async
+            | InstructionClass::JumpDirect
+            | InstructionClass::CallDirect
+            | InstructionClass::MovCr3
+            | InstructionClass::Other => {}
+        }
+    }
+    count
+}
+
+/// Retuns Ok(Some(ip)) if it can compute next ip from instruction, and it is not the subsequent
+/// instruction in the code. Returns Ok(None) if the next instruction is the following in the code.
+///Returns Err if decoding needs trace to proceed.
+fn next_ip(inst: &DecodedInstruction) -> Result<Option<u64>, ()> {
+    match inst.class {
+        InstructionClass::Other => Ok(None),
+        InstructionClass::JumpDirect | InstructionClass::CallDirect => {
+            let target = inst.branch_target;
+            if target == inst.next_ip {
+                Ok(None)
+            } else {
+                Ok(Some(target))
+            }
+        }
+        InstructionClass::Async
+        | InstructionClass::JumpIndirect
+        | InstructionClass::MovCr3
+        | InstructionClass::CallIndirect
+        | InstructionClass::CondBranch
+        | InstructionClass::FarCall
+        | InstructionClass::FarJump
+        | InstructionClass::FarReturn
+        | InstructionClass::Return
+        | InstructionClass::VmEntry => Err(()),
+    }
+}
+
+/// Returns the coverage map index of the edge `from` -> `to`, None if the map is empty.
+const fn coverage_entry(from: u64, to: u64, map_len: usize) -> Option<usize> {
+    ((fmix64(from) ^ fmix64(to)) as usize).checked_rem(map_len)
+}
+
+/// Update `state` with the status carried by a PSB+.
+///
+/// The state that PSB+ does not carry (e.g. the return compression stack) is preserved.
+fn decode_psbplus<CE: CoverageEntry>(
+    iteration_state: &mut CovDecIterationState<CE>,
+    builder: &PtCoverageDecoderBuilder,
+    state: &mut ExecutionState,
+) -> Result<(), PtDecoderError> {
+    state.tip_last_ip = 0; // SDM 34.4.2.2 “Last IP” is reset by PSB
+    state.packet_en = false; // A FUP is part of PSB+ only if PacketEn is set
+    state.vm_entry_pending = false;
+
+    loop {
+        match iteration_state.packet_decoder.next_packet()? {
+            PtPacket::PsbEnd(..) => break,
+            // Tolerate a missing PSBEND, e.g. in a trace sliced right after a PSB: the flow starts
+            // at the TIP.PGE, which is processed as usual
+            PtPacket::TipPge(..) => {
+                iteration_state.packet_decoder.push_back();
+                break;
+            }
+            PtPacket::TntShort(..) | PtPacket::TntLong(..)
+                if builder.tolerate_missing_psbend && state.packet_en =>
+            {
+                iteration_state.packet_decoder.push_back();
+                break;
+            }
+            // Back-to-back PSBs (e.g. a capture aligned on a ring buffer boundary): the PSB+
+            // status follows the last one
+            PtPacket::Psb(..) => {
+                state.tip_last_ip = 0;
+                state.packet_en = false;
+            }
+            #[cfg(feature = "tsc")]
+            PtPacket::Tsc(tsc) => state.tsc = Some(tsc.tsc()),
+            // Timing packets do not affect the coverage
+            #[cfg(all(feature = "tsc", feature = "mtc"))]
+            PtPacket::Tma(..) => {}
+            PtPacket::Pip(pip) => {
+                if let Some(event) = state.update_pip(pip, builder) {
+                    iteration_state.event(event);
+                }
+            }
+            PtPacket::Vmcs(vmcs) => state.vmcs = Some(vmcs),
+            #[cfg(feature = "cbr")]
+            PtPacket::Cbr(cbr) => {
+                if let Some(event) = state.update_cbr(cbr) {
+                    iteration_state.event(event);
+                }
+            }
+            PtPacket::ModeTsx(mode_tsx) => state.mode_tsx = mode_tsx,
+            PtPacket::ModeExec(mode_exec) => state.update_mode_exec(mode_exec),
+            PtPacket::Fup(fup) => {
+                // fixme: if the decoder was already running, consider also that some code executed
+                // between PSB's preceeding packet and PSB might get ignored here
+                if let Some(last_ip) = decode_psbplus_fup(fup, state.tip_last_ip, builder.cpu) {
+                    if builder.check_canonical_ip && !is_canonical_48(last_ip) {
+                        return Err(PtDecoderError::NonCanonicalIp(last_ip));
+                    }
+                    state.packet_en = true;
+                    state.tip_last_ip = last_ip;
+                    state.ip = last_ip;
+                } else {
+                    state.packet_en = false;
+                }
+            }
+            #[cfg(feature = "cyc")]
+            PtPacket::Cyc(..) => {}
+            #[cfg(feature = "mtc")]
+            PtPacket::Mtc(..) => {}
+            // The status is incomplete after an OVF, it is reported as any unexpected packet
+            packet => return Err(PtDecoderError::MalformedPsbPlus { packet }),
+        }
+    }
+
+    // The CR3 of the PSB+ PIP, if any, is needed to resolve the image. Kernel code is not walked
+    // with userspace_only, its images are not needed.
+    if builder.check_psbplus_ip
+        && state.packet_en
+        && !builder.images.is_empty()
+        && !(builder.userspace_only && state.ip >= builder.kernel_address_start)
+        && state.resolve_image(&builder.images, state.ip).is_none()
+    {
+        return Err(PtDecoderError::PsbPlusIpOutsideImages(state.ip));
+    }
+
+    let position = iteration_state.packet_decoder.last_packet_range().end;
+    state.flow_start_position.get_or_insert(position);
+    Ok(())
+}
+
+fn handle_trig<CE: CoverageEntry>(
+    trig: Trig,
+    builder: &PtCoverageDecoderBuilder,
+    iteration_state: &mut CovDecIterationState<CE>,
+) {
+    let trigger = Trigger {
+        trigger_bits: trig.trigger_bits(),
+        multiple: trig.multiple(),
+        ip: trig.ip(),
+        instruction_count: trig.instruction_count(),
+        offset: iteration_state.packet_decoder.last_packet_range().start,
+    };
+    if let Some(Callback(sink)) = &builder.trigger_sink {
+        sink.lock().unwrap_or_else(PoisonError::into_inner)(&trigger);
+    }
+    iteration_state.event(PtDecoderEvent::Trigger(trigger));
+}
+
+fn decode_psbplus_fup(fup: Fup, mut last_ip: u64, cpu: Option<PtCpu>) -> Option<u64> {
+    if let Some(cpu) = cpu
+        && cpu.errata().bdm70
+    {
+        // todo: pt_evt_check_bdm70
+    }
+    fup.ip(&mut last_ip).then_some(last_ip)
+}
+
+/// Control flow edge recorded in the coverage map
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Edge {
+    /// IP following the branch instruction, or the FUP IP for asynchronous events
+    pub from: u64,
+    /// Branch target
+    pub to: u64,
+    /// Index of the image containing `from`, see
+    /// [`PtCoverageDecoderBuilder::edge_image_indices`]
+    pub from_image: Option<usize>,
+    /// Index of the image containing `to`, see [`PtCoverageDecoderBuilder::edge_image_indices`]
+    pub to_image: Option<usize>,
+    /// Class of the instruction that produced the edge, [`InstructionClass::Async`] for
+    /// asynchronous events
+    pub class: InstructionClass,
+}
+
+/// Event reported by [`PtCoverageDecoder::coverage_with_events`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum PtDecoderEvent {
+    /// A PIP changed the CR3, i.e. the address space. The first PIP of the trace only sets the
+    /// initial CR3.
+    PagingChange {
+        old_cr3: u64,
+        new_cr3: u64,
+        /// The new address space is a VMX non-root one, i.e. a guest
+        non_root_vmx: bool,
+    },
+    /// A TRIG packet reported that some triggers fired
+    Trigger(Trigger),
+    /// A CBR changed the core:bus ratio, i.e. the core frequency. The first CBR of the trace only
+    /// sets the initial ratio.
+    #[cfg(feature = "cbr")]
+    CoreBusRatioChange { old_ratio: u8, new_ratio: u8 },
+    /// A CFE reported a control flow event, e.g. an interrupt
+    #[cfg(feature = "event")]
+    ControlFlowEvent {
+        /// Type of the event
+        cfe_type: u8,
+        /// Vector of the event, e.g. the interrupt vector
+        vector: u8,
+        /// IP the event is bound to, None if the CFE has no IP
+        ip: Option<u64>,
+    },
+}
+
+/// Triggers reported by a TRIG packet, see [`PtCoverageDecoderBuilder::trigger_sink`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Trigger {
+    /// Bit vector of the triggers that fired
+    pub trigger_bits: u8,
+    /// A trigger fired multiple times since the previous TRIG
+    pub multiple: bool,
+    /// The IP of the instruction that fired the trigger is carried by the following FUP
+    pub ip: bool,
+    /// Instruction count (ICNT) field of the TRIG
+    pub instruction_count: u8,
+    /// Offset of the TRIG packet in the trace
+    pub offset: usize,
+}
+
+/// Privilege ring, see [`PtCoverageDecoderBuilder::ring_filter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Ring {
+    /// Ring 0
+    Kernel,
+    /// Ring 3
+    User,
+}
+
+impl Ring {
+    /// Ring entered by `instruction`, None if it is not a ring transition
+    const fn after(instruction: &Instruction) -> Option<Self> {
+        match instruction.code() {
+            Code::Syscall
+            | Code::Sysenter
+            | Code::Int_imm8
+            | Code::Int3
+            | Code::Int1
+            | Code::Into => Some(Self::Kernel),
+            Code::Sysretd
+            | Code::Sysretq
+            | Code::Sysexitd
+            | Code::Sysexitq
+            | Code::Iretw
+            | Code::Iretd
+            | Code::Iretq
+            | Code::Eretu => Some(Self::User),
+            _ => None,
+        }
+    }
+}
+
+/// Classification of an instruction according to its effect on the control flow, and therefore
+/// on the PT packets it generates.
+///
+/// Instructions are classified with `InstructionClass::from(&iced_x86::Instruction)`, using the
+/// `iced-x86` 1.21 crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum InstructionClass {
+    /// Does not change the control flow
+    Other,
+
+    /// Not an instruction: interrupt, exception, VM exit or TSX abort, reported by a FUP and a TIP
+    Async,
+
+    /// Near call with the target encoded in the instruction
+    CallDirect,
+    /// Near call with the target in a register or in memory, generates a TIP
+    CallIndirect,
+    /// Conditional branch, generates a TNT
+    CondBranch,
+    /// Far call, interrupt, exception or system call
+    FarCall,
+    /// Far jump
+    FarJump,
+    /// Far return, interrupt return or system call return
+    FarReturn,
+    /// Near jump with the target encoded in the instruction
+    JumpDirect,
+    /// Near jump with the target in a register or in memory, generates a TIP
+    JumpIndirect,
+    /// MOV to CR3, generates a PIP
+    MovCr3,
+    /// Near return, generates a TIP or a TNT if return compression is enabled
+    Return,
+    /// VMLAUNCH or VMRESUME, generates a TIP to the guest, possibly preceded by a PIP and a VMCS
+    VmEntry,
+}
+
+impl From<&Instruction> for InstructionClass {
+    fn from(instruction: &Instruction) -> Self {
+        match instruction.flow_control() {
+            FlowControl::Next => {
+                if matches!(instruction.code(), Code::Mov_cr_r32 | Code::Mov_cr_r64)
+                    && instruction.op0_register() == Register::CR3
+                {
+                    Self::MovCr3
+                } else {
+                    Self::Other
+                }
+            }
+            FlowControl::ConditionalBranch => Self::CondBranch,
+            FlowControl::UnconditionalBranch => {
+                if instruction.is_jmp_far() {
+                    Self::FarJump
+                } else if is_jmpabs(instruction) {
+                    Self::JumpIndirect
+                } else {
+                    Self::JumpDirect
+                }
+            }
+            FlowControl::Interrupt | FlowControl::Exception => Self::FarCall,
+            FlowControl::Return => match instruction.code() {
+                Code::Retnd
+                | Code::Retnq
+                | Code::Retnw
+                | Code::Retnd_imm16
+                | Code::Retnq_imm16
+                | Code::Retnw_imm16 => Self::Return,
+                _ => Self::FarReturn,
+            },
+            FlowControl::IndirectBranch => {
+                if instruction.is_jmp_far_indirect() {
+                    Self::FarJump
+                } else {
+                    Self::JumpIndirect
+                }
+            }
+            FlowControl::Call => {
+                if instruction.is_call_near() {
+                    return Self::CallDirect;
+                }
+
+                match instruction.code() {
+                    Code::Vmlaunch | Code::Vmresume => Self::VmEntry,
+                    _ => Self::FarCall,
+                }
+            }
+            FlowControl::IndirectCall => {
+                if instruction.is_call_far_indirect() {
+                    Self::FarCall
+                } else {
+                    Self::CallIndirect
+                }
+            }
+            FlowControl::XbeginXabortXend => {
+                // todo: support transactions
+                Self::Other
+            }
+        }
+    }
+}
+
+/// APX JMPABS encodes its target in the instruction, but libipt (and the PT hardware) treats it as
+/// an indirect jump, generating a TIP.
+///
+/// Enabled with `--cfg ptcov_apx` once iced_x86 decodes APX/REX2 instructions.
+#[cfg(ptcov_apx)]
+fn is_jmpabs(instruction: &Instruction) -> bool {
+    matches!(instruction.code(), Code::Jmpabs_imm64)
+}
+
+#[cfg(not(ptcov_apx))]
+const fn is_jmpabs(_instruction: &Instruction) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "retc")]
+    use crate::coverage_decoder::ExecutionState;
+    use crate::coverage_decoder::{
+        DecoderScratch, Edge, InstructionClass, PtCoverageDecoderBuilder, PtCoverageSession,
+        PtDecoderError, PtDecoderEvent, Ring, StaticEdgeCount, Trigger, classify_from,
+        coverage_entry,
+    };
+    use crate::image::PtImage;
+    use crate::packet::mode::{AddressingMode, TransactionState};
+    use crate::packet::ovf::Ovf;
+    use crate::packet::psb::Psb;
+    use crate::packet::tnt::TntShort;
+    use crate::packet::{PtPacket, PtPacketParseError};
+    use crate::test_utils::{
+        MODE_EXEC_64, PSBEND, SAMPLE_TIP_CALLEE, sample_builder, sample_decoder, sample_image,
+        sample_trace, trace, transaction_trace,
+    };
+    use iced_x86::{Code, Instruction, Register};
+    use std::collections::HashSet;
+    use std::mem;
+    use std::sync::atomic::{AtomicU8, Ordering};
+    use std::sync::{Arc, Mutex, mpsc};
+
+    #[test]
+    fn error_hint() {
+        let mut decoder = sample_decoder();
+        // TNT without TIP.PGE
+        let trace_disabled = trace(&[&Psb::CONTENT, &MODE_EXEC_64, &PSBEND, &[0x04]]);
+        let state = decoder
+            .coverage(&trace_disabled, &mut [0u8; 64])
+            .unwrap_err();
+        assert_eq!(state, PtDecoderError::IncoherentState);
+        assert!(state.hint().unwrap().contains("tracing is disabled"));
+
+        let mut decoder = sample_decoder();
+        // TIP while the walk reaches the jne at 0x1003
+        let trace_image = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &SAMPLE_TIP_CALLEE,  // TIP 0x1020
+        ]);
+        let image = decoder.coverage(&trace_image, &mut [0u8; 64]).unwrap_err();
+        assert_eq!(image, PtDecoderError::IncoherentImage);
+        assert!(
+            image
+                .hint()
+                .unwrap()
+                .contains("don't match the provided image")
+        );
+
+        assert_eq!(PtDecoderError::Eof.hint(), None);
+    }
+
+    #[test]
+    fn from_packet_parse_error() {
+        assert_eq!(
+            PtDecoderError::from(PtPacketParseError::Eof),
+            PtDecoderError::Eof
+        );
+        assert_eq!(
+            PtDecoderError::from(PtPacketParseError::MalformedPacket),
+            PtDecoderError::MalformedPacket
+        );
+        assert_eq!(
+            PtDecoderError::from(PtPacketParseError::Truncated),
+            PtDecoderError::TruncatedTrace
+        );
+        assert_eq!(
+            PtDecoderError::from(PtPacketParseError::UnknownPacket { b0: 0x99, b1: None }),
+            PtDecoderError::UnknownPacket { b0: 0x99, b1: None }
+        );
+    }
+
+    #[test]
+    fn truncated_trace() {
+        let builder = sample_builder();
+        let trace = sample_trace();
+        // Cut in the middle of TIP 0x1020
+        let end = Psb::SIZE + MODE_EXEC_64.len() + PSBEND.len() + 3 + 1 + 2;
+
+        let mut decoder = builder.clone().build().unwrap();
+        assert_eq!(
+            decoder.coverage(&trace[..end], &mut [0u8; 16]),
+            Err(PtDecoderError::TruncatedTrace)
+        );
+
+        // Cut right after it
+        let mut decoder = builder.build().unwrap();
+        assert_eq!(decoder.coverage(&trace[..end + 1], &mut [0u8; 16]), Ok(()));
+    }
+
+    #[test]
+    fn non_canonical_ip() {
+        // TIP.PGE 48 bit IP, bit 47 set while last IP upper bytes are 0
+        let tip_pge = [0x91, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80];
+        let trace = trace(&[&Psb::CONTENT, &PSBEND, &tip_pge]);
+        let mut coverage = [0u8; 16];
+
+        let mut decoder = PtCoverageDecoderBuilder::new().build().unwrap();
+        assert_eq!(decoder.coverage(&trace, &mut coverage), Ok(()));
+
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .check_canonical_ip(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            decoder.coverage(&trace, &mut coverage),
+            Err(PtDecoderError::NonCanonicalIp(0x8000_0000_0000))
+        );
+    }
+
+    #[test]
+    fn psbplus_ip_outside_images() {
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &[0x3d, 0x00, 0x50], // FUP 0x5000
+            &PSBEND,
+        ]);
+        let builder = sample_builder();
+        let mut coverage = [0u8; 16];
+
+        let mut decoder = builder.clone().build().unwrap();
+        assert_eq!(decoder.coverage(&trace, &mut coverage), Ok(()));
+
+        let mut decoder = builder.check_psbplus_ip(true).build().unwrap();
+        assert_eq!(
+            decoder.coverage(&trace, &mut coverage),
+            Err(PtDecoderError::PsbPlusIpOutsideImages(0x5000))
+        );
+    }
+
+    #[test]
+    fn suppressed_tip_pge_ip() {
+        let trace = trace(&[&Psb::CONTENT, &PSBEND, &[0x11]]);
+        let mut coverage = [0u8; 16];
+
+        let mut decoder = PtCoverageDecoderBuilder::new().build().unwrap();
+        assert_eq!(
+            decoder.coverage(&trace, &mut coverage),
+            Err(PtDecoderError::IpSuppressed)
+        );
+    }
+
+    #[test]
+    fn from_works() {
+        for i in Code::Add_rm8_r8 as u16..=Code::VEX_Vsm3rnds2_xmm_xmm_xmmm128_imm8 as u16 {
+            let code: Code = unsafe { mem::transmute(i) };
+
+            // Ignore IA-64 leftovers
+            if matches!(
+                code,
+                Code::Jmpe_disp16 | Code::Jmpe_disp32 | Code::Jmpe_rm16 | Code::Jmpe_rm32
+            ) {
+                continue;
+            }
+
+            // Ignore not Intel stuff (AMD SVM, Cyryx, ...)
+            if matches!(
+                code,
+                Code::Vmrunw
+                    | Code::Vmrund
+                    | Code::Vmrunq
+                    | Code::Vmmcall
+                    | Code::Skinit
+                    | Code::Vmgexit
+                    | Code::Smint
+                    | Code::Dmint
+                    | Code::Rdm
+                    | Code::Smint_0F7E
+                    | Code::Altinst
+                    | Code::Vmgexit_F2
+            ) {
+                continue;
+            }
+
+            // Ignore mvex
+            if matches!(
+                code,
+                Code::VEX_KNC_Jkzd_kr_rel8_64
+                    | Code::VEX_KNC_Jknzd_kr_rel8_64
+                    | Code::VEX_KNC_Jkzd_kr_rel32_64
+                    | Code::VEX_KNC_Jknzd_kr_rel32_64
+            ) {
+                continue;
+            }
+
+            // todo: SMM stuff
+            if matches!(code, Code::Rsm) {
+                continue;
+            }
+
+            //todo: TDX stuff
+            if matches!(code, Code::Tdcall | Code::Seamret | Code::Seamcall) {
+                continue;
+            }
+
+            let mut instruction = Instruction::new();
+            instruction.set_code(code);
+            assert_eq!(
+                InstructionClass::from(&instruction),
+                class_from_instruction_raw(&instruction),
+                "{i} - {code:?}"
+            );
+        }
+    }
+
+    const fn class_from_instruction_raw(instruction: &Instruction) -> InstructionClass {
+        use Code::*;
         match instruction.code() {
             // PTI_INST_CALL_FFr2 indirect
             Call_rm16 | Call_rm32 | Call_rm64 => InstructionClass::CallIndirect,
@@ -1003,23 +3188,24 @@ mod test {
             Sysexitd | Sysexitq |
             // PTI_INST_SYSRET
             Sysretd | Sysretq |
-            // PTI_INST_VMLAUNCH
-            Vmlaunch |
-            // PTI_INST_VMRESUME
-            Vmresume |
             // PTI_INST_UIRET
             Uiret |
             // PTI_INST_ERETS
             Erets |
             // PTI_INST_ERETU
             Eretu => InstructionClass::FarReturn,
+            // PTI_INST_VMLAUNCH
+            Vmlaunch |
+            // PTI_INST_VMRESUME
+            Vmresume => InstructionClass::VmEntry,
             // PTI_INST_JMP_FFr4 indirect
             Jmp_rm16 | Jmp_rm32 | Jmp_rm64 => InstructionClass::JumpIndirect,
+            // PTI_INST_JMPABS direct, but it behaves like an indirect jump according to libipt
+            #[cfg(ptcov_apx)]
+            Jmpabs_imm64 => InstructionClass::JumpIndirect,
             // PTI_INST_JMP_E9 direct
             Jmp_rel16 | Jmp_rel32_32 | Jmp_rel32_64 |
             // PTI_INST_JMP_EB direct
-            // todo: when iced_x86 will introduce APX (https://en.wikipedia.org/wiki/X86#APX) and REX2
-            // support, also check for what in libipt they call PTI_INST_JMPABS direct but behaves like an indirect jump according to libipt
             Jmp_rel8_16 | Jmp_rel8_32 | Jmp_rel8_64 => InstructionClass::JumpDirect,
             // PTI_INST_MOV_CR3
             Mov_cr_r64 | Mov_cr_r32 if matches!(instruction.op0_register(), Register::CR3) => InstructionClass::MovCr3,
@@ -1031,4 +3217,2114 @@ mod test {
             _ => InstructionClass::Other
         }
     }
+
+    #[cfg(ptcov_apx)]
+    #[test]
+    fn jmpabs_is_indirect() {
+        let mut instruction = Instruction::new();
+        instruction.set_code(Code::Jmpabs_imm64);
+        assert_eq!(
+            InstructionClass::from(&instruction),
+            InstructionClass::JumpIndirect
+        );
+        assert_eq!(
+            class_from_instruction_raw(&instruction),
+            InstructionClass::JumpIndirect
+        );
+    }
+
+    #[test]
+    fn vm_entry() {
+        // 0x1000: vmlaunch
+        let host = PtImage::new(vec![0x0f, 0x01, 0xc2], 0x1000);
+        // 0x4000: jmp rax
+        let guest = PtImage::new(vec![0xff, 0xe0], 0x4000);
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10],                               // TIP.PGE 0x1000
+            &[0x02, 0x43, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00], // PIP, NR
+            &[0x02, 0xc8, 0x01, 0x00, 0x00, 0x00, 0x00],       // VMCS
+            &[0x2d, 0x00, 0x40],                               // TIP 0x4000
+            &[0x01],                                           // TIP.PGD, IP suppressed
+        ]);
+
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .images(vec![host, guest])
+            .filter_vmx_non_root(true)
+            .build()
+            .unwrap();
+        let mut coverage = [0u8; 64];
+        assert_eq!(decoder.coverage(&trace, &mut coverage), Ok(()));
+        assert_eq!(coverage.iter().map(|&c| c as usize).sum::<usize>(), 1);
+        assert_eq!(coverage[coverage_entry(0x1003, 0x4000, 64).unwrap()], 1);
+        assert!(decoder.state.vmcs.is_some());
+        assert!(!decoder.state.vm_entry_pending);
+        assert!(decoder.state.pip.non_root_vmx());
+    }
+
+    #[test]
+    fn current_vmcs() {
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &[0x02, 0xc8, 0x45, 0x23, 0x01, 0x00, 0x00], // VMCS
+            &MODE_EXEC_64,
+            &PSBEND,
+        ]);
+        let mut decoder = sample_decoder();
+        assert_eq!(decoder.current_vmcs(), None);
+        assert_eq!(decoder.coverage(&trace, &mut [0u8; 16]), Ok(()));
+        assert_eq!(decoder.current_vmcs(), Some(0x1234_5000));
+    }
+
+    #[test]
+    fn async_transfer() {
+        // Interrupt before `call rbx`, the handler at 0x1020 returns with tracing disabled
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x04],             // TNT N
+            &[0x3d, 0x05, 0x10], // FUP 0x1005
+            &MODE_EXEC_64,
+            &SAMPLE_TIP_CALLEE, // TIP 0x1020
+            &[0x01],            // TIP.PGD, IP suppressed
+        ]);
+        let mut decoder = sample_decoder();
+        let mut coverage = [0u8; 64];
+        let mut edges = vec![];
+        assert_eq!(
+            decoder.coverage_with_edges(&trace, &mut coverage, &mut edges),
+            Ok(())
+        );
+        assert_eq!(coverage.iter().map(|&c| c as usize).sum::<usize>(), 1);
+        assert_eq!(coverage[coverage_entry(0x1005, 0x1020, 64).unwrap()], 1);
+        assert_eq!(
+            edges
+                .iter()
+                .map(|e| (e.from, e.to, e.class))
+                .collect::<Vec<_>>(),
+            [(0x1005, 0x1020, InstructionClass::Async)]
+        );
+        assert_eq!(decoder.state.ip, 0x1021);
+    }
+
+    #[test]
+    fn classify_from_sample_image() {
+        let image = sample_image();
+        assert_eq!(
+            classify_from(&image, 0x1000, AddressingMode::_64),
+            Ok((0x1003, InstructionClass::CondBranch))
+        );
+        // jmp 0x1000 is followed
+        assert_eq!(
+            classify_from(&image, 0x1007, AddressingMode::_64),
+            Ok((0x1003, InstructionClass::CondBranch))
+        );
+        assert_eq!(
+            classify_from(&image, 0x1005, AddressingMode::_64),
+            Ok((0x1005, InstructionClass::CallIndirect))
+        );
+        assert_eq!(
+            classify_from(&image, 0x100a, AddressingMode::_64),
+            Ok((0x1020, InstructionClass::Return))
+        );
+        assert_eq!(
+            classify_from(&image, 0x2000, AddressingMode::_64),
+            Err(PtDecoderError::MissingImage { address: 0x2000 })
+        );
+        // jmp $
+        let image = PtImage::new(vec![0xeb, 0xfe], 0x1000);
+        assert_eq!(
+            classify_from(&image, 0x1000, AddressingMode::_64),
+            Err(PtDecoderError::InfiniteLoop(0x1000))
+        );
+    }
+
+    #[test]
+    fn userspace_only() {
+        // 0x1000: syscall
+        // 0x1002: jmp rax
+        // ...
+        // 0x1010: ret
+        let mut data = vec![0x90; 0x11];
+        data[..4].copy_from_slice(&[0x0f, 0x05, 0xff, 0xe0]);
+        data[0x10] = 0xc3;
+        let image = PtImage::new(data, 0x1000);
+        let tip_64 = |ip: u64| [[0xcd].as_slice(), &ip.to_le_bytes()].concat();
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10],            // TIP.PGE 0x1000
+            &tip_64(0xffff_ffff_8100_0000), // TIP to the syscall handler
+            &[0x06],                        // TNT T, in the kernel
+            &tip_64(0x1002),                // TIP back to userspace (sysret)
+            &[0x2d, 0x10, 0x10],            // TIP 0x1010
+            &[0x01],                        // TIP.PGD, IP suppressed
+        ]);
+        let builder = PtCoverageDecoderBuilder::new().images(vec![image]);
+        let mut coverage = [0u8; 64];
+
+        assert_eq!(
+            builder
+                .clone()
+                .build()
+                .unwrap()
+                .coverage(&trace, &mut coverage),
+            Err(PtDecoderError::MissingImage {
+                address: 0xffff_ffff_8100_0000
+            })
+        );
+
+        coverage.fill(0);
+        let mut decoder = builder.userspace_only(true).build().unwrap();
+        assert_eq!(decoder.coverage(&trace, &mut coverage), Ok(()));
+        assert_eq!(coverage.iter().map(|&c| c as usize).sum::<usize>(), 1);
+        assert_eq!(coverage[coverage_entry(0x1004, 0x1010, 64).unwrap()], 1);
+    }
+
+    #[test]
+    fn tnt_spanning_kernel_entry() {
+        // 0x1000: jne 0x1002
+        // 0x1002: syscall
+        // 0x1004: jmp rax
+        // ...
+        // 0x1010: ret
+        let mut data = vec![0x90; 0x11];
+        data[..6].copy_from_slice(&[0x75, 0x00, 0x0f, 0x05, 0xff, 0xe0]);
+        data[0x10] = 0xc3;
+        let tip_64 = |ip: u64| [[0xcd].as_slice(), &ip.to_le_bytes()].concat();
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10],            // TIP.PGE 0x1000
+            &[0x16],                        // TNT N T T, T T in the kernel
+            &tip_64(0xffff_ffff_8100_0000), // TIP to the syscall handler
+            &tip_64(0x1004),                // TIP back to userspace (sysret), skipped
+            &[0x2d, 0x10, 0x10],            // TIP 0x1010, skipped
+            &[0x01],                        // TIP.PGD, IP suppressed
+            &[0x31, 0x04, 0x10],            // TIP.PGE 0x1004
+            &[0x2d, 0x10, 0x10],            // TIP 0x1010
+            &[0x01],                        // TIP.PGD, IP suppressed
+        ]);
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .images(vec![PtImage::new(data, 0x1000)])
+            .userspace_only(true)
+            .build()
+            .unwrap();
+        let mut coverage = [0u8; 64];
+        assert_eq!(decoder.coverage(&trace, &mut coverage), Ok(()));
+        assert_eq!(coverage.iter().map(|&c| c as usize).sum::<usize>(), 1);
+        assert_eq!(coverage[coverage_entry(0x1006, 0x1010, 64).unwrap()], 1);
+    }
+
+    #[test]
+    fn ring_filter() {
+        // 0x1000: syscall
+        // 0x1002: jmp rax
+        // ...
+        // 0x1010: ret
+        let mut user = vec![0x90; 0x11];
+        user[..4].copy_from_slice(&[0x0f, 0x05, 0xff, 0xe0]);
+        user[0x10] = 0xc3;
+        // 0x2000: jmp rax
+        // ...
+        // 0x2010: sysretq
+        let mut kernel = vec![0x90; 0x13];
+        kernel[..2].copy_from_slice(&[0xff, 0xe0]);
+        kernel[0x10..].copy_from_slice(&[0x48, 0x0f, 0x07]);
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x2d, 0x00, 0x20], // TIP 0x2000, syscall
+            &[0x2d, 0x10, 0x20], // TIP 0x2010
+            &[0x2d, 0x02, 0x10], // TIP 0x1002, sysret
+            &[0x2d, 0x10, 0x10], // TIP 0x1010
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+        let hits = |ring| {
+            let mut decoder = PtCoverageDecoderBuilder::new()
+                .images(vec![
+                    PtImage::new(user.clone(), 0x1000),
+                    PtImage::new(kernel.clone(), 0x2000),
+                ])
+                .ring_filter(ring)
+                .build()
+                .unwrap();
+            let mut coverage = [0u8; 64];
+            decoder.coverage(&trace, &mut coverage).unwrap();
+            [
+                (0x1002, 0x2000),
+                (0x2002, 0x2010),
+                (0x2013, 0x1002),
+                (0x1004, 0x1010),
+            ]
+            .map(|(from, to)| coverage[coverage_entry(from, to, 64).unwrap()])
+        };
+
+        assert_eq!(hits(None), [1, 1, 1, 1]);
+        assert_eq!(hits(Some(Ring::Kernel)), [1, 1, 0, 0]);
+        assert_eq!(hits(Some(Ring::User)), [0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn ring_filter_kernel_iret() {
+        // 0x1000: syscall
+        let user = vec![0x0f, 0x05];
+        // 0x2000: iretq
+        // ...
+        // 0x2010: jmp rax
+        let mut kernel = vec![0x90; 0x12];
+        kernel[..2].copy_from_slice(&[0x48, 0xcf]);
+        kernel[0x10..].copy_from_slice(&[0xff, 0xe0]);
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x2d, 0x00, 0x20], // TIP 0x2000, syscall
+            &[0x2d, 0x10, 0x20], // TIP 0x2010, iretq
+            &[0x2d, 0x00, 0x20], // TIP 0x2000
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+        let hits = |ring| {
+            let mut decoder = PtCoverageDecoderBuilder::new()
+                .images(vec![
+                    PtImage::new(user.clone(), 0x1000),
+                    PtImage::new(kernel.clone(), 0x2000),
+                ])
+                .ring_filter(Some(ring))
+                .build()
+                .unwrap();
+            let mut coverage = [0u8; 64];
+            decoder.coverage(&trace, &mut coverage).unwrap();
+            [(0x1002, 0x2000), (0x2002, 0x2010), (0x2012, 0x2000)]
+                .map(|(from, to)| coverage[coverage_entry(from, to, 64).unwrap()])
+        };
+
+        // The kernel to kernel IRET is taken for a return to userspace
+        assert_eq!(hits(Ring::Kernel), [1, 0, 0]);
+        assert_eq!(hits(Ring::User), [0, 1, 1]);
+    }
+
+    #[test]
+    fn back_to_back_psbs() {
+        let sample = sample_trace();
+        let flow = &sample[Psb::SIZE + MODE_EXEC_64.len() + PSBEND.len()..];
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &PSBEND,
+            &Psb::CONTENT,
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            flow,
+        ]);
+
+        let mut expected = [0u8; 64];
+        let mut coverage = [0u8; 64];
+        let builder = sample_builder();
+        builder
+            .clone()
+            .build()
+            .unwrap()
+            .coverage(&sample, &mut expected)
+            .unwrap();
+        let mut decoder = builder.build().unwrap();
+        assert_eq!(decoder.coverage(&trace, &mut coverage), Ok(()));
+        assert_eq!(coverage, expected);
+    }
+
+    #[test]
+    fn unknown_mode_leaf() {
+        let sample = sample_trace();
+        let pge_end = Psb::SIZE + MODE_EXEC_64.len() + PSBEND.len() + 3;
+        let unknown_mode = [0x99, 0x40];
+        let trace = trace(&[&sample[..pge_end], &unknown_mode, &sample[pge_end..]]);
+        let builder = sample_builder();
+
+        let mut coverage = [0u8; 64];
+        assert_eq!(
+            builder
+                .clone()
+                .build()
+                .unwrap()
+                .coverage(&trace, &mut coverage),
+            Err(PtDecoderError::UnknownPacket {
+                b0: 0x99,
+                b1: Some(0x40)
+            })
+        );
+
+        let mut expected = [0u8; 64];
+        builder
+            .clone()
+            .build()
+            .unwrap()
+            .coverage(&sample, &mut expected)
+            .unwrap();
+        let mut coverage = [0u8; 64];
+        let mut decoder = builder.skip_unknown_mode(true).build().unwrap();
+        assert_eq!(decoder.coverage(&trace, &mut coverage), Ok(()));
+        assert_eq!(coverage, expected);
+    }
+
+    #[test]
+    fn transaction_state() {
+        let mut decoder = PtCoverageDecoderBuilder::new().build().unwrap();
+        let mut coverage = [0u8; 16];
+        assert_eq!(decoder.transaction_state(), TransactionState::Commit);
+
+        let sync = trace(&[&Psb::CONTENT, &PSBEND]);
+        decoder.coverage(&sync, &mut coverage).unwrap();
+        for (mode_tsx, state) in [
+            (0x21, TransactionState::Begin),
+            (0x20, TransactionState::Commit),
+            (0x21, TransactionState::Begin),
+            (0x22, TransactionState::Abort),
+        ] {
+            decoder.coverage(&[0x99, mode_tsx], &mut coverage).unwrap();
+            assert_eq!(decoder.transaction_state(), state);
+        }
+    }
+
+    #[test]
+    fn exclude_aborted_transactions() {
+        let begin = transaction_trace();
+        let commit = trace(&[
+            &begin,
+            &[0x99, 0x20],       // MODE.TSX Commit
+            &[0x3d, 0x20, 0x10], // FUP 0x1020
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+        let abort = trace(&[
+            &begin,
+            &[0x99, 0x22],       // MODE.TSX Abort
+            &[0x3d, 0x20, 0x10], // FUP 0x1020
+            &[0x2d, 0x09, 0x10], // TIP 0x1009, abort handler
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+        let call = coverage_entry(0x1007, 0x1020, 64).unwrap();
+        let abort_handler = coverage_entry(0x1020, 0x1009, 64).unwrap();
+
+        for (exclude, trace, expected) in [
+            (false, &commit, [1, 0]),
+            (true, &commit, [1, 0]),
+            (false, &abort, [1, 1]),
+            (true, &abort, [0, 1]),
+        ] {
+            let mut decoder = sample_builder()
+                .exclude_aborted_transactions(exclude)
+                .build()
+                .unwrap();
+            let mut coverage = [0u8; 64];
+            assert_eq!(decoder.coverage(trace, &mut coverage), Ok(()));
+            assert_eq!([coverage[call], coverage[abort_handler]], expected);
+        }
+
+        // The transaction commits in a call with a smaller map
+        let mut decoder = sample_builder()
+            .exclude_aborted_transactions(true)
+            .build()
+            .unwrap();
+        let mut large = vec![0u8; 1 << 16];
+        assert!(coverage_entry(0x1007, 0x1020, large.len()).unwrap() >= 8);
+        assert_eq!(decoder.coverage(&begin, &mut large), Ok(()));
+        let mut small = [0u8; 8];
+        assert_eq!(decoder.coverage(&commit[begin.len()..], &mut small), Ok(()));
+        assert!(large.iter().all(|&cell| cell == 0));
+        assert_eq!(small[coverage_entry(0x1007, 0x1020, 8).unwrap()], 1);
+    }
+
+    #[test]
+    fn resolve_image() {
+        let other = PtImage::new(vec![0x90; 0x10], 0x2000);
+        let decoder = PtCoverageDecoderBuilder::new()
+            .images(vec![sample_image(), other.clone()])
+            .build()
+            .unwrap();
+
+        assert_eq!(decoder.resolve_image(0x1000), Some(&sample_image()));
+        assert_eq!(decoder.resolve_image(0x102f), Some(&sample_image()));
+        assert_eq!(decoder.resolve_image(0x2008), Some(&other));
+        assert_eq!(decoder.resolve_image(0x1030), None);
+        assert_eq!(decoder.resolve_image(0x2010), None);
+    }
+
+    #[test]
+    fn edge_image_indices() {
+        // 0x2000: jmp rax
+        // 0x2002: nop
+        let other = PtImage::new(vec![0xff, 0xe0, 0x90], 0x2000);
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x04],             // TNT N
+            &[0x2d, 0x00, 0x20], // TIP 0x2000
+            &[0x2d, 0x20, 0x10], // TIP 0x1020
+        ]);
+        let builder = PtCoverageDecoderBuilder::new().images(vec![sample_image(), other]);
+        let mut coverage = [0u8; 64];
+
+        let mut edges = vec![];
+        let mut decoder = builder.clone().build().unwrap();
+        assert_eq!(
+            decoder.coverage_with_edges(&trace, &mut coverage, &mut edges),
+            Ok(())
+        );
+        assert_eq!(
+            edges,
+            [
+                Edge {
+                    from: 0x1007,
+                    to: 0x2000,
+                    from_image: None,
+                    to_image: None,
+                    class: InstructionClass::CallIndirect
+                },
+                Edge {
+                    from: 0x2002,
+                    to: 0x1020,
+                    from_image: None,
+                    to_image: None,
+                    class: InstructionClass::JumpIndirect
+                }
+            ]
+        );
+
+        let mut edges = vec![];
+        let mut decoder = builder.edge_image_indices(true).build().unwrap();
+        assert_eq!(
+            decoder.coverage_with_edges(&trace, &mut coverage, &mut edges),
+            Ok(())
+        );
+        assert_eq!(
+            edges
+                .iter()
+                .map(|e| (e.from_image, e.to_image))
+                .collect::<Vec<_>>(),
+            [(Some(0), Some(1)), (Some(1), Some(0))]
+        );
+    }
+
+    #[test]
+    fn skip_missing_images() {
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x04],             // TNT N
+            &[0x2d, 0x00, 0x30], // TIP 0x3000, not mapped
+            &[0x06],             // TNT T
+            &[0x01],             // TIP.PGD, IP suppressed
+            &[0x31, 0x07, 0x10], // TIP.PGE 0x1007
+            &[0x06],             // TNT T
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+        let builder = sample_builder();
+        let mut coverage = [0u8; 64];
+
+        let mut decoder = builder.clone().build().unwrap();
+        assert_eq!(
+            decoder.coverage(&trace, &mut coverage),
+            Err(PtDecoderError::MissingImage { address: 0x3000 })
+        );
+
+        let mut edges = vec![];
+        let mut decoder = builder.skip_missing_images(true).build().unwrap();
+        assert_eq!(
+            decoder.coverage_with_edges(&trace, &mut coverage, &mut edges),
+            Ok(())
+        );
+        assert_eq!(
+            edges.iter().map(|e| (e.from, e.to)).collect::<Vec<_>>(),
+            [(0x1007, 0x3000), (0x1005, 0x1009)]
+        );
+    }
+
+    #[test]
+    fn required_image_ranges() {
+        let builder = PtCoverageDecoderBuilder::new();
+        assert_eq!(
+            builder.required_image_ranges(&sample_trace(), 0),
+            Ok(vec![(0x1000, 0x1001), (0x1007, 0x1008), (0x1020, 0x1021)])
+        );
+        // The IPs of the loop at 0x1000 are merged, the callee at 0x1020 is further away
+        assert_eq!(
+            builder.required_image_ranges(&sample_trace(), 0x10),
+            Ok(vec![(0x1000, 0x1008), (0x1020, 0x1021)])
+        );
+
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x2d, 0x01, 0x10], // TIP 0x1001
+            &[0x2d, 0x00, 0x10], // TIP 0x1000
+            &[0x2d, 0x03, 0x10], // TIP 0x1003
+        ]);
+        assert_eq!(
+            builder.required_image_ranges(&trace, 0),
+            Ok(vec![(0x1000, 0x1002), (0x1003, 0x1004)])
+        );
+    }
+
+    #[test]
+    fn tip_edges() {
+        let builder = PtCoverageDecoderBuilder::new();
+        assert_eq!(
+            builder.tip_edges(&sample_trace()),
+            Ok(vec![(0x1000, 0x1020), (0x1020, 0x1007)])
+        );
+
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &[0x3d, 0x00, 0x10], // FUP 0x1000
+            &PSBEND,
+            &[0x2d, 0x20, 0x10], // TIP 0x1020
+            &[0x3d, 0x07, 0x10], // FUP 0x1007
+            &[0x2d, 0x00, 0x20], // TIP 0x2000
+            &[0x21, 0x09, 0x10], // TIP.PGD 0x1009
+            &[0x31, 0x00, 0x30], // TIP.PGE 0x3000
+            &[0x2d, 0x10, 0x30], // TIP 0x3010
+        ]);
+        assert_eq!(
+            builder.tip_edges(&trace),
+            Ok(vec![(0x1020, 0x1007), (0x1007, 0x2000), (0x3000, 0x3010)])
+        );
+    }
+
+    #[test]
+    fn cr3_images() {
+        // 0x1000: jmp rax
+        let other = PtImage::new(vec![0xff, 0xe0], 0x1000);
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &[0x02, 0x43, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00], // PIP CR3 0x1000
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10],                               // TIP.PGE 0x1000
+            &[0x04],                                           // TNT N
+            &SAMPLE_TIP_CALLEE,                                // TIP 0x1020
+            &[0x01],                                           // TIP.PGD, IP suppressed
+            &[0x02, 0x43, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00], // PIP CR3 0x2000
+            &[0x31, 0x00, 0x10],                               // TIP.PGE 0x1000
+            &[0x2d, 0x00, 0x20],                               // TIP 0x2000
+        ]);
+        let mut coverage = [0u8; 64];
+
+        let mut edges = vec![];
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .cr3_images(vec![(0x1000, vec![sample_image()]), (0x2000, vec![other])])
+            .edge_image_indices(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            decoder.coverage_with_edges(&trace, &mut coverage, &mut edges),
+            Ok(())
+        );
+        assert_eq!(
+            edges.iter().map(|e| (e.from, e.to)).collect::<Vec<_>>(),
+            [(0x1007, 0x1020), (0x1002, 0x2000)]
+        );
+        // The image of the other CR3 overlapping 0x1002 is not picked
+        assert_eq!(
+            edges
+                .iter()
+                .map(|e| (e.from_image, e.to_image))
+                .collect::<Vec<_>>(),
+            [(Some(0), Some(0)), (None, None)]
+        );
+    }
+
+    #[test]
+    fn exclude_cr3() {
+        // 0x1000: jmp rax
+        let other = PtImage::new(vec![0xff, 0xe0], 0x1000);
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &[0x02, 0x43, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00], // PIP CR3 0x1000
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10],                               // TIP.PGE 0x1000
+            &[0x04],                                           // TNT N
+            &SAMPLE_TIP_CALLEE,                                // TIP 0x1020
+            &[0x01],                                           // TIP.PGD, IP suppressed
+            &[0x02, 0x43, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00], // PIP CR3 0x2000
+            &[0x31, 0x00, 0x10],                               // TIP.PGE 0x1000
+            &[0x2d, 0x00, 0x20],                               // TIP 0x2000
+        ]);
+        let mut coverage = [0u8; 64];
+
+        let mut edges = vec![];
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .cr3_images(vec![(0x1000, vec![sample_image()]), (0x2000, vec![other])])
+            .exclude_cr3(0x2000)
+            .build()
+            .unwrap();
+        assert_eq!(
+            decoder.coverage_with_edges(&trace, &mut coverage, &mut edges),
+            Ok(())
+        );
+        assert_eq!(
+            edges.iter().map(|e| (e.from, e.to)).collect::<Vec<_>>(),
+            [(0x1007, 0x1020)]
+        );
+        assert_eq!(coverage.iter().filter(|&&c| c != 0).count(), 1);
+    }
+
+    #[test]
+    fn mask_range() {
+        let mut decoder = sample_builder().mask_range(0x1020, 0x1030).build().unwrap();
+
+        let mut edges = vec![];
+        assert_eq!(
+            decoder.coverage_with_edges(&sample_trace(), &mut [0u8; 64], &mut edges),
+            Ok(())
+        );
+        // The call to 0x1020 is dropped, the ret from the masked code is still decoded
+        assert_eq!(
+            edges.iter().map(|e| (e.from, e.to)).collect::<Vec<_>>(),
+            [(0x1021, 0x1007), (0x1005, 0x1009)]
+        );
+    }
+
+    #[test]
+    fn filter_en_ranges() {
+        // 0x1000: jmp 0x5000, out of the filter range and of the images
+        let image = PtImage::new(vec![0xe9, 0xfb, 0x3f, 0x00, 0x00], 0x1000);
+        let builder = PtCoverageDecoderBuilder::new().images(vec![image]);
+        let suppressed = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+        let mut decoder = builder.clone().build().unwrap();
+        assert_eq!(
+            decoder.coverage(&suppressed, &mut [0u8; 16]),
+            Err(PtDecoderError::MissingImage { address: 0x5000 })
+        );
+
+        let builder = builder
+            .filter_en_ranges(&[(0x1000, 0x2000)])
+            .check_filter_en_ranges(true);
+        let mut decoder = builder.clone().build().unwrap();
+        assert_eq!(decoder.coverage(&suppressed, &mut [0u8; 16]), Ok(()));
+        assert_eq!(decoder.state.ip, 0x5000);
+
+        let mut decoder = builder.clone().build().unwrap();
+        let pgd_outside = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x21, 0x00, 0x50], // TIP.PGD 0x5000
+        ]);
+        assert_eq!(decoder.coverage(&pgd_outside, &mut [0u8; 16]), Ok(()));
+
+        let mut decoder = builder.build().unwrap();
+        let pge_outside = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x50], // TIP.PGE 0x5000
+        ]);
+        assert_eq!(
+            decoder.coverage(&pge_outside, &mut [0u8; 16]),
+            Err(PtDecoderError::FilterEnRangesMismatch(0x5000))
+        );
+    }
+
+    #[test]
+    fn coverage_hash() {
+        let mut map = [0u8; 64];
+        map[3] = 1;
+        map[40] = 7;
+        let hash = super::coverage_hash(&map);
+        assert_eq!(super::coverage_hash(&map.clone()), hash);
+
+        let mut other = map;
+        other[63] ^= 0x01;
+        assert_ne!(super::coverage_hash(&other), hash);
+
+        // The position of the hits matters
+        let mut swapped = map;
+        swapped.swap(3, 40);
+        assert_ne!(super::coverage_hash(&swapped), hash);
+
+        // Maps of other cell types
+        assert_ne!(
+            super::coverage_hash(&[true, false]),
+            super::coverage_hash(&[false, true])
+        );
+        assert_ne!(
+            super::coverage_hash(&[0u32; 2]),
+            super::coverage_hash(&[0u32; 3])
+        );
+    }
+
+    #[test]
+    fn static_edge_count() {
+        // 0x1000: jne 0x1000
+        // 0x1002: jrcxz 0x1000
+        // 0x1004: jmp rax
+        // 0x1006: jmp 0x1000
+        let image = PtImage::new(vec![0x75, 0xfe, 0xe3, 0xfc, 0xff, 0xe0, 0xeb, 0xf8], 0x1000);
+        let count = super::static_edge_count(&image, AddressingMode::_64);
+        assert_eq!(
+            count,
+            StaticEdgeCount {
+                cond_branches: 2,
+                indirect_branches: 1
+            }
+        );
+
+        // jne, call rbx and two rets
+        let count = super::static_edge_count(&sample_image(), AddressingMode::_64);
+        assert_eq!(count.total(), 4);
+
+        let mut edges = vec![];
+        let mut decoder = sample_decoder();
+        decoder
+            .coverage_with_edges(&sample_trace(), &mut [0u8; 64], &mut edges)
+            .unwrap();
+        let covered = edges.iter().map(|e| (e.from, e.to)).collect::<HashSet<_>>();
+        assert_eq!(count.ratio(covered.len()), 0.75);
+        assert_eq!(StaticEdgeCount::default().ratio(1), 0.0);
+    }
+
+    #[test]
+    fn coverage_per_image() {
+        // 0x2000: jmp rax
+        // 0x2002: nop
+        let other = PtImage::new(vec![0xff, 0xe0, 0x90], 0x2000);
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x04],             // TNT N
+            &[0x2d, 0x00, 0x20], // TIP 0x2000
+            &[0x2d, 0x20, 0x10], // TIP 0x1020
+            &[0x2d, 0x00, 0x30], // TIP 0x3000, outside the images
+        ]);
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .images(vec![sample_image(), other])
+            .build()
+            .unwrap();
+        let mut sample_coverage = [0u8; 16];
+        let mut other_coverage = [0u8; 32];
+
+        assert_eq!(
+            decoder.coverage_per_image(&trace, &mut [&mut sample_coverage]),
+            Err(PtDecoderError::InvalidArgument)
+        );
+        assert_eq!(
+            decoder.coverage_per_image(&trace, &mut [&mut sample_coverage, &mut other_coverage]),
+            Ok(())
+        );
+
+        let mut expected = [0u8; 16];
+        expected[coverage_entry(0x2002, 0x1020, 16).unwrap()] = 1;
+        assert_eq!(sample_coverage, expected);
+        let mut expected = [0u8; 32];
+        expected[coverage_entry(0x1007, 0x2000, 32).unwrap()] = 1;
+        assert_eq!(other_coverage, expected);
+    }
+
+    #[test]
+    fn relative_coverage() {
+        let relocated = PtImage::new(sample_image().data().to_vec(), 0x5000);
+        let relocated_trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x50], // TIP.PGE 0x5000
+            &[0x04],             // TNT N
+            &[0x2d, 0x20, 0x50], // TIP 0x5020
+            &[0x2d, 0x07, 0x50], // TIP 0x5007
+            &[0x06],             // TNT T
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+
+        let coverage = |relative, image: &PtImage, trace: &[u8]| {
+            let mut coverage = [0u8; 64];
+            PtCoverageDecoderBuilder::new()
+                .images(vec![image.clone()])
+                .relative_coverage(relative)
+                .build()
+                .unwrap()
+                .coverage(trace, &mut coverage)
+                .unwrap();
+            coverage
+        };
+
+        let absolute = coverage(false, &sample_image(), &sample_trace());
+        assert_ne!(absolute, coverage(false, &relocated, &relocated_trace));
+
+        let relative = coverage(true, &sample_image(), &sample_trace());
+        assert_eq!(relative.iter().filter(|&&c| c != 0).count(), 3);
+        assert_eq!(relative, coverage(true, &relocated, &relocated_trace));
+    }
+
+    #[test]
+    fn paging_change_event() {
+        const PIP_1000: [u8; 8] = [0x02, 0x43, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00];
+        const PIP_2000_NR: [u8; 8] = [0x02, 0x43, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00];
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &PIP_1000,
+            &PSBEND,
+            &PIP_2000_NR,
+            &Psb::CONTENT,
+            &PIP_2000_NR, // Unchanged
+            &PSBEND,
+            &PIP_1000,
+        ]);
+        let mut decoder = PtCoverageDecoderBuilder::new().build().unwrap();
+
+        let mut events = vec![];
+        assert_eq!(
+            decoder.coverage_with_events(&trace, &mut [0u8; 16], &mut events),
+            Ok(())
+        );
+        assert_eq!(
+            events,
+            [
+                PtDecoderEvent::PagingChange {
+                    old_cr3: 0x1000,
+                    new_cr3: 0x2000,
+                    non_root_vmx: true
+                },
+                PtDecoderEvent::PagingChange {
+                    old_cr3: 0x2000,
+                    new_cr3: 0x1000,
+                    non_root_vmx: false
+                }
+            ]
+        );
+    }
+
+    #[cfg(feature = "cbr")]
+    #[test]
+    fn core_bus_ratio_change() {
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &[0x02, 0x03, 0x20, 0x00], // CBR 0x20
+            &PSBEND,
+            &[0x31, 0x00, 0x10],       // TIP.PGE 0x1000
+            &[0x02, 0x03, 0x20, 0x00], // CBR 0x20, unchanged
+            &[0x04],                   // TNT N
+            &[0x02, 0x03, 0x28, 0x00], // CBR 0x28
+            &SAMPLE_TIP_CALLEE,
+        ]);
+        let mut decoder = sample_decoder();
+        assert_eq!(decoder.current_cbr(), None);
+
+        let mut events = vec![];
+        assert_eq!(
+            decoder.coverage_with_events(&trace, &mut [0u8; 16], &mut events),
+            Ok(())
+        );
+        assert_eq!(
+            events,
+            [PtDecoderEvent::CoreBusRatioChange {
+                old_ratio: 0x20,
+                new_ratio: 0x28
+            }]
+        );
+        assert_eq!(decoder.current_cbr(), Some(0x28));
+    }
+
+    #[test]
+    fn trigger_event() {
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0xd9, 0x83, 0x02], // TRIG
+            &[0x04],             // TNT N
+        ]);
+        let mut decoder = sample_decoder();
+
+        let mut events = vec![];
+        assert_eq!(
+            decoder.coverage_with_events(&trace, &mut [0u8; 16], &mut events),
+            Ok(())
+        );
+        assert_eq!(
+            events,
+            [PtDecoderEvent::Trigger(Trigger {
+                trigger_bits: 0x3,
+                multiple: false,
+                ip: true,
+                instruction_count: 2,
+                offset: Psb::SIZE + MODE_EXEC_64.len() + PSBEND.len() + 3
+            })]
+        );
+
+        let triggers = Arc::new(Mutex::new(vec![]));
+        let sink_triggers = triggers.clone();
+        let mut decoder = sample_builder()
+            .trigger_sink(move |trigger| sink_triggers.lock().unwrap().push(*trigger))
+            .build()
+            .unwrap();
+        assert_eq!(decoder.coverage(&trace, &mut [0u8; 16]), Ok(()));
+        assert_eq!(
+            triggers
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|&trigger| PtDecoderEvent::Trigger(trigger))
+                .collect::<Vec<_>>(),
+            events
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "event")]
+    fn cfe_binds_fup_ip() {
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10],                         // TIP.PGE 0x1000
+            &[0x02, 0x13, 0x81, 0x20],                   // CFE interrupt, vector 0x20, IP
+            &[0x02, 0x53, 0x01, 0, 0, 0, 0, 0, 0, 0, 0], // EVD
+            &[0x3d, 0x03, 0x10],                         // FUP 0x1003
+            &SAMPLE_TIP_CALLEE,                          // TIP 0x1020
+        ]);
+        let mut decoder = sample_decoder();
+
+        let mut coverage = [0u8; 16];
+        let mut events = vec![];
+        assert_eq!(
+            decoder.coverage_with_events(&trace, &mut coverage, &mut events),
+            Ok(())
+        );
+        assert_eq!(
+            events,
+            [PtDecoderEvent::ControlFlowEvent {
+                cfe_type: 1,
+                vector: 0x20,
+                ip: Some(0x1003)
+            }]
+        );
+        let entry = coverage_entry(0x1003, 0x1020, coverage.len()).unwrap();
+        assert_eq!(coverage[entry], 1);
+        assert_eq!(decoder.state.ip, 0x1020);
+    }
+
+    #[test]
+    fn indirect_calls() {
+        let mut decoder = sample_decoder();
+
+        let mut calls = vec![];
+        assert_eq!(
+            decoder.coverage_with_indirect_calls(&sample_trace(), &mut [0u8; 64], &mut calls),
+            Ok(())
+        );
+        // The edges of the ret and of the taken jne are not recorded
+        assert_eq!(calls, [(0x1007, 0x1020)]);
+
+        // The transaction aborts in the callee, the abort handler is not a call
+        let abort = trace(&[
+            &transaction_trace(),
+            &[0x99, 0x22],       // MODE.TSX Abort
+            &[0x3d, 0x20, 0x10], // FUP 0x1020
+            &[0x2d, 0x09, 0x10], // TIP 0x1009, abort handler
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+        let mut calls = vec![];
+        assert_eq!(
+            decoder.coverage_with_indirect_calls(&abort, &mut [0u8; 64], &mut calls),
+            Ok(())
+        );
+        assert_eq!(calls, [(0x1007, 0x1020)]);
+    }
+
+    #[test]
+    fn edge_classes() {
+        let mut decoder = sample_decoder();
+
+        let mut edges = vec![];
+        assert_eq!(
+            decoder.coverage_with_edges(&sample_trace(), &mut [0u8; 64], &mut edges),
+            Ok(())
+        );
+        assert_eq!(
+            edges
+                .iter()
+                .map(|e| (e.from, e.to, e.class))
+                .collect::<Vec<_>>(),
+            [
+                (0x1007, 0x1020, InstructionClass::CallIndirect),
+                (0x1021, 0x1007, InstructionClass::Return),
+                (0x1005, 0x1009, InstructionClass::CondBranch),
+            ]
+        );
+    }
+
+    #[test]
+    fn consecutive_tips() {
+        // 0x1000: jmp rax
+        // 0x1010: jmp rbx
+        // 0x1020: jne 0x1000
+        let mut data = vec![0x90; 0x30];
+        data[..2].copy_from_slice(&[0xff, 0xe0]);
+        data[0x10..0x12].copy_from_slice(&[0xff, 0xe3]);
+        data[0x20..0x22].copy_from_slice(&[0x75, 0xde]);
+        let image = PtImage::new(data, 0x1000);
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x2d, 0x10, 0x10], // TIP 0x1010
+            &SAMPLE_TIP_CALLEE,  // TIP 0x1020
+            &[0x06],             // TNT T
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .images(vec![image])
+            .build()
+            .unwrap();
+
+        let mut edges = vec![];
+        assert_eq!(
+            decoder.coverage_with_edges(&trace, &mut [0u8; 64], &mut edges),
+            Ok(())
+        );
+        assert_eq!(
+            edges.iter().map(|e| (e.from, e.to)).collect::<Vec<_>>(),
+            [(0x1002, 0x1010), (0x1012, 0x1020), (0x1022, 0x1000)]
+        );
+    }
+
+    #[test]
+    fn next_edge() {
+        // sample_trace with a TRIG, twice in a row
+        let psb_plus: &[&[u8]] = &[&Psb::CONTENT, &MODE_EXEC_64, &PSBEND];
+        let flow: &[&[u8]] = &[
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0xd9, 0x83, 0x02], // TRIG
+            &[0x04],             // TNT N
+            &SAMPLE_TIP_CALLEE,  // TIP 0x1020
+            &[0x2d, 0x07, 0x10], // TIP 0x1007
+            &[0x06],             // TNT T
+            &[0x01],             // TIP.PGD, IP suppressed
+        ];
+        let trace = trace(&[psb_plus, flow, psb_plus, flow].concat());
+        let triggers = Arc::new(Mutex::new(vec![]));
+        let sink_triggers = triggers.clone();
+        let builder = sample_builder()
+            .trigger_sink(move |trigger| sink_triggers.lock().unwrap().push(*trigger));
+
+        let mut edges = vec![];
+        let mut decoder = builder.clone().build().unwrap();
+        assert_eq!(
+            decoder.coverage_with_edges(&trace, &mut [0u8; 64], &mut edges),
+            Ok(())
+        );
+        let batch_triggers = mem::take(&mut *triggers.lock().unwrap());
+        assert_eq!(batch_triggers.len(), 2);
+
+        let mut decoder = builder.build().unwrap();
+        let mut stepper = decoder.edge_stepper(&trace).unwrap();
+        let mut stepped = vec![];
+        while let Some(edge) = stepper.next_edge().unwrap() {
+            stepped.push(edge);
+        }
+        assert_eq!(stepper.next_edge(), Ok(None));
+        assert_eq!(
+            stepped,
+            edges.iter().map(|e| (e.from, e.to)).collect::<Vec<_>>()
+        );
+        assert_eq!(stepped.len(), 6);
+        // Same offsets, relative to the whole trace
+        assert_eq!(*triggers.lock().unwrap(), batch_triggers);
+    }
+
+    #[test]
+    fn progress() {
+        let trace = sample_trace();
+        let mut decoder = sample_decoder();
+        assert_eq!(decoder.progress(), 0.0);
+
+        // Progress after stepping 1, 2, ... edges, then the whole trace
+        let mut progress = vec![];
+        for steps in 1.. {
+            let mut stepper = decoder.edge_stepper(&trace).unwrap();
+            let mut stepped = 0;
+            while stepped < steps && stepper.next_edge().unwrap().is_some() {
+                stepped += 1;
+            }
+            drop(stepper);
+            progress.push(decoder.progress());
+            if stepped < steps {
+                break;
+            }
+        }
+        assert!(progress[0] > 0.0 && progress[0] < 1.0);
+        assert!(progress.windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(progress.last(), Some(&1.0));
+        assert_eq!(decoder.trace_len(), trace.len());
+
+        // Also tracked by the other decoding calls
+        let mut decoder = sample_decoder();
+        assert!(decoder.run_to_end(&trace).is_ok());
+        assert_eq!(decoder.progress(), 1.0);
+    }
+
+    #[test]
+    fn assume_enabled_at() {
+        // sample_trace sliced after its TIP.PGE
+        let trace = trace(&[
+            &[0x04],             // TNT N
+            &SAMPLE_TIP_CALLEE,  // TIP 0x1020
+            &[0x2d, 0x07, 0x10], // TIP 0x1007
+            &[0x06],             // TNT T
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+        let builder = sample_builder();
+        let mut decoder = builder.clone().build().unwrap();
+        assert_eq!(
+            decoder.coverage(&trace, &mut [0u8; 64]),
+            Err(PtDecoderError::SyncFailed)
+        );
+
+        let mut decoder = builder.assume_enabled_at(0x1000).build().unwrap();
+        let mut edges = vec![];
+        assert_eq!(
+            decoder.coverage_with_edges(&trace, &mut [0u8; 64], &mut edges),
+            Ok(())
+        );
+        assert_eq!(
+            edges.iter().map(|e| (e.from, e.to)).collect::<Vec<_>>(),
+            [(0x1007, 0x1020), (0x1021, 0x1007), (0x1005, 0x1009)]
+        );
+        assert!(!decoder.state.packet_en);
+    }
+
+    #[test]
+    fn skip_initial_psb() {
+        // sample_trace sliced after its PSB+
+        let trace = sample_trace();
+        let flow = &trace[Psb::SIZE + MODE_EXEC_64.len() + PSBEND.len()..];
+        let builder = sample_builder();
+        let mut decoder = builder.clone().build().unwrap();
+        assert_eq!(
+            decoder.coverage(flow, &mut [0u8; 64]),
+            Err(PtDecoderError::SyncFailed)
+        );
+
+        let mut decoder = builder.skip_initial_psb(false).build().unwrap();
+        let mut edges = vec![];
+        assert_eq!(
+            decoder.coverage_with_edges(flow, &mut [0u8; 64], &mut edges),
+            Ok(())
+        );
+        // The leading TIP.PGE enabled tracing
+        assert_eq!(edges.len(), 3);
+        assert_eq!(edges[0].from, 0x1007);
+    }
+
+    #[test]
+    fn decode_until_offset() {
+        let trace = sample_trace();
+        // Up to the TIP 0x1007, the last TNT is not decoded
+        let tnt_t = trace.len() - 2;
+        let mut decoder = sample_builder().decode_until_offset(tnt_t).build().unwrap();
+
+        let mut edges = vec![];
+        assert_eq!(
+            decoder.coverage_with_edges(&trace, &mut [0u8; 64], &mut edges),
+            Ok(())
+        );
+        assert_eq!(
+            edges.iter().map(|e| (e.from, e.to)).collect::<Vec<_>>(),
+            [(0x1007, 0x1020), (0x1021, 0x1007)]
+        );
+        assert!(decoder.state.packet_en);
+        assert_eq!(decoder.progress(), tnt_t as f32 / trace.len() as f32);
+
+        let mut stepper = decoder.edge_stepper(&trace).unwrap();
+        while stepper.next_edge().unwrap().is_some() {}
+        drop(stepper);
+        assert_eq!(decoder.progress(), tnt_t as f32 / trace.len() as f32);
+    }
+
+    #[test]
+    fn debug_summary() {
+        let mut decoder = sample_decoder();
+        let trace = sample_trace();
+        decoder.edge_stepper(&trace).unwrap().next_edge().unwrap();
+
+        let debug = format!("{decoder:?}");
+        assert!(debug.contains("packet_en: true"));
+        assert!(debug.contains("ip: 0x1020"));
+        // The images are not dumped
+        assert!(!debug.contains("data"));
+    }
+
+    #[test]
+    fn decoder_options() {
+        // 0x1000: lock add eax, ebx ; invalid, LOCK needs a memory destination
+        // 0x1003: ret
+        let image = PtImage::new(vec![0xf0, 0x01, 0xd8, 0xc3], 0x1000);
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+        let builder = PtCoverageDecoderBuilder::new().images(vec![image]);
+
+        let mut decoder = builder.clone().build().unwrap();
+        assert_eq!(
+            decoder.coverage(&trace, &mut [0u8; 16]),
+            Err(PtDecoderError::MalformedInstruction)
+        );
+
+        let mut decoder = builder
+            .decoder_options(iced_x86::DecoderOptions::NO_INVALID_CHECK)
+            .build()
+            .unwrap();
+        assert_eq!(decoder.coverage(&trace, &mut [0u8; 16]), Ok(()));
+    }
+
+    #[test]
+    fn initial_addressing_mode() {
+        // 64-bit: mov rax, 0x275_0000 ; ret
+        // 16-bit: dec ax ; mov ax, 0 ; jne ...
+        let mut data = vec![
+            0x48, 0xb8, 0x00, 0x00, 0x75, 0x02, 0x00, 0x00, 0x00, 0x00, 0xc3,
+        ];
+        data.resize(0x30, 0x90);
+        let image = PtImage::new(data, 0x1000);
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &PSBEND,             // No MODE.Exec
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &SAMPLE_TIP_CALLEE,  // TIP 0x1020
+        ]);
+        let builder = PtCoverageDecoderBuilder::new().images(vec![image]);
+
+        let mut decoder = builder
+            .clone()
+            .initial_addressing_mode(AddressingMode::_64)
+            .build()
+            .unwrap();
+        assert_eq!(decoder.coverage(&trace, &mut [0u8; 16]), Ok(()));
+
+        let mut decoder = builder
+            .initial_addressing_mode(AddressingMode::_16)
+            .build()
+            .unwrap();
+        assert_eq!(
+            decoder.coverage(&trace, &mut [0u8; 16]),
+            Err(PtDecoderError::IncoherentImage)
+        );
+    }
+
+    #[test]
+    fn mode_exec_seen() {
+        let mut decoder = sample_decoder();
+        assert!(!decoder.mode_exec_seen());
+
+        let psb = trace(&[&Psb::CONTENT, &PSBEND]);
+        assert_eq!(decoder.coverage(&psb, &mut [0u8; 16]), Ok(()));
+        assert!(!decoder.mode_exec_seen());
+
+        let tip_pge: &[u8] = &[0x31, 0x00, 0x10]; // TIP.PGE 0x1000
+        let mode_exec = trace(&[&MODE_EXEC_64, tip_pge]);
+        assert_eq!(decoder.coverage(&mode_exec, &mut [0u8; 16]), Ok(()));
+        assert!(decoder.mode_exec_seen());
+    }
+
+    #[test]
+    fn build_with_scratch() {
+        let builder = sample_builder();
+        let trace = sample_trace();
+
+        let mut expected = [0u8; 64];
+        let mut decoder = builder.clone().build().unwrap();
+        decoder.coverage(&trace, &mut expected).unwrap();
+
+        let mut scratch = DecoderScratch::new();
+        for _ in 0..2 {
+            let mut coverage = [0u8; 64];
+            let mut decoder = builder.clone().build_with_scratch(scratch).unwrap();
+            decoder.coverage(&trace, &mut coverage).unwrap();
+            assert_eq!(coverage, expected);
+
+            scratch = decoder.into_scratch();
+            assert!(scratch.proceed_inst_cache.is_empty());
+            assert!(scratch.proceed_inst_cache.capacity() > 0);
+        }
+    }
+
+    #[test]
+    fn session() {
+        let builder = sample_builder();
+        let mut expected = [0u8; 64];
+        builder
+            .clone()
+            .build()
+            .unwrap()
+            .coverage(&sample_trace(), &mut expected)
+            .unwrap();
+
+        let mut session = PtCoverageSession::new(builder).unwrap();
+        let mut coverage = [0u8; 64];
+        assert_eq!(session.decode(&sample_trace(), &mut coverage), Ok(()));
+        assert_eq!(coverage, expected);
+        let builds = session.decoder.inst_decoder_builds;
+        assert!(builds > 0);
+
+        // The second trace is decoded from scratch, using the warm caches
+        let mut coverage = [0u8; 64];
+        assert_eq!(session.decode(&sample_trace(), &mut coverage), Ok(()));
+        assert_eq!(coverage, expected);
+        assert_eq!(session.decoder.inst_decoder_builds, builds);
+    }
+
+    #[test]
+    fn run_to_end() {
+        let image = sample_image();
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .images(vec![image.clone()])
+            .build()
+            .unwrap();
+        let ip = decoder.run_to_end(&sample_trace()).unwrap();
+        assert!(image.contains(ip));
+    }
+
+    #[test]
+    fn integrity_check() {
+        let segments = [
+            sample_trace(),
+            trace(&[&Psb::CONTENT, &MODE_EXEC_64, &PSBEND]),
+        ];
+        let checksum = |bytes: &[u8]| bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        let checksums: Vec<u8> = segments.iter().map(|segment| checksum(segment)).collect();
+        let mut pt_trace = segments.concat();
+
+        let checked = Arc::new(Mutex::new(vec![]));
+        let hook_checked = checked.clone();
+        let mut decoder = sample_builder()
+            .integrity_check(move |segment| {
+                let mut checked = hook_checked.lock().unwrap();
+                let ok = checksum(segment) == checksums[checked.len()];
+                checked.push(segment.len());
+                ok
+            })
+            .build()
+            .unwrap();
+        assert_eq!(decoder.coverage(&pt_trace, &mut [0u8; 64]), Ok(()));
+        assert_eq!(
+            *checked.lock().unwrap(),
+            [segments[0].len(), segments[1].len()]
+        );
+
+        // TNT N turned into TNT T in the first segment
+        checked.lock().unwrap().clear();
+        let tnt = pt_trace.iter().position(|&b| b == 0x04).unwrap();
+        pt_trace[tnt] = 0x06;
+        assert_eq!(
+            decoder.coverage(&pt_trace, &mut [0u8; 64]),
+            Err(PtDecoderError::IntegrityCheckFailed)
+        );
+        assert_eq!(checked.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn instruction_hook() {
+        let (sender, receiver) = mpsc::channel();
+        let mut walked = 0;
+        let mut decoder = sample_builder()
+            .instruction_hook(move |ins| {
+                walked += 1;
+                sender.send((walked, ins.ip())).unwrap();
+            })
+            .build()
+            .unwrap();
+        decoder.coverage(&sample_trace(), &mut [0u8; 64]).unwrap();
+        // The caches filled by the first decoding are bypassed
+        decoder.coverage(&sample_trace(), &mut [0u8; 64]).unwrap();
+        let (walked, ips): (Vec<_>, Vec<_>) = receiver.try_iter().unzip();
+        assert_eq!(walked, (1..=16).collect::<Vec<_>>());
+        // test, jne, call, ret, jmp, test, jne, ret, for each decoding
+        assert_eq!(
+            ips,
+            [
+                0x1000, 0x1003, 0x1005, 0x1020, 0x1007, 0x1000, 0x1003, 0x1009
+            ]
+            .repeat(2)
+        );
+    }
+
+    #[test]
+    fn instruction_ips() {
+        let mut decoder = sample_decoder();
+        // Fill the caches, the walk must not skip instructions
+        decoder.coverage(&sample_trace(), &mut [0u8; 64]).unwrap();
+
+        let mut ips = vec![];
+        assert_eq!(
+            decoder.coverage_with_instruction_ips(&sample_trace(), &mut [0u8; 64], &mut ips),
+            Ok(())
+        );
+        // test, jne, call, ret, jmp, test, jne, ret
+        assert_eq!(
+            ips,
+            [
+                0x1000, 0x1003, 0x1005, 0x1020, 0x1007, 0x1000, 0x1003, 0x1009
+            ]
+        );
+    }
+
+    #[test]
+    fn tip_pge_without_psbend() {
+        let builder = sample_builder();
+        let mut expected = vec![];
+        let mut decoder = builder.clone().build().unwrap();
+        decoder
+            .coverage_with_edges(&sample_trace(), &mut [0u8; 64], &mut expected)
+            .unwrap();
+
+        let sliced = trace(&[
+            &Psb::CONTENT,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x04],             // TNT N
+            &SAMPLE_TIP_CALLEE,  // TIP 0x1020
+            &[0x2d, 0x07, 0x10], // TIP 0x1007
+            &[0x06],             // TNT T
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+        // Not gated by tolerate_missing_psbend
+        let builder = builder.tolerate_missing_psbend(false);
+        let mut edges = vec![];
+        let mut decoder = builder.clone().build().unwrap();
+        assert_eq!(
+            decoder.coverage_with_edges(&sliced, &mut [0u8; 64], &mut edges),
+            Ok(())
+        );
+        assert_eq!(edges, expected);
+
+        // The TIP.PGE ends the PSB+, the PSBEND that follows it is out of place
+        let pge_then_psbend = trace(&[
+            &Psb::CONTENT,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &PSBEND,
+        ]);
+        let mut decoder = builder.build().unwrap();
+        assert!(matches!(
+            decoder.coverage(&pge_then_psbend, &mut [0u8; 64]),
+            Err(PtDecoderError::InvalidPacketSequence { .. })
+        ));
+    }
+
+    #[test]
+    fn tolerate_missing_psbend() {
+        let builder = sample_builder();
+        let mut expected = vec![];
+        let mut decoder = builder.clone().build().unwrap();
+        decoder
+            .coverage_with_edges(&sample_trace(), &mut [0u8; 64], &mut expected)
+            .unwrap();
+
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &[0x3d, 0x00, 0x10], // FUP 0x1000
+            &[0x04],             // TNT N
+            &SAMPLE_TIP_CALLEE,  // TIP 0x1020
+            &[0x2d, 0x07, 0x10], // TIP 0x1007
+            &[0x06],             // TNT T
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+        let mut decoder = builder.clone().build().unwrap();
+        assert!(matches!(
+            decoder.coverage(&trace, &mut [0u8; 64]),
+            Err(PtDecoderError::MalformedPsbPlus { .. })
+        ));
+
+        let mut edges = vec![];
+        let mut decoder = builder.tolerate_missing_psbend(true).build().unwrap();
+        assert_eq!(
+            decoder.coverage_with_edges(&trace, &mut [0u8; 64], &mut edges),
+            Ok(())
+        );
+        assert_eq!(edges, expected);
+    }
+
+    #[test]
+    fn malformed_psbplus_packet() {
+        let tnt = trace(&[&Psb::CONTENT, &MODE_EXEC_64, &[0x04], &PSBEND]); // TNT N
+        let mut decoder = PtCoverageDecoderBuilder::new().build().unwrap();
+        assert_eq!(
+            decoder.coverage(&tnt, &mut [0u8; 16]),
+            Err(PtDecoderError::MalformedPsbPlus {
+                packet: PtPacket::TntShort(TntShort::from_bits(&[false]).unwrap())
+            })
+        );
+
+        let ovf = trace(&[&Psb::CONTENT, &[0x02, Ovf::B1], &PSBEND]);
+        let mut decoder = PtCoverageDecoderBuilder::new().build().unwrap();
+        assert_eq!(
+            decoder.coverage(&ovf, &mut [0u8; 16]),
+            Err(PtDecoderError::MalformedPsbPlus {
+                packet: PtPacket::Ovf(Ovf {})
+            })
+        );
+    }
+
+    #[test]
+    fn infinite_loop() {
+        // jmp $
+        let image = PtImage::new(vec![0xeb, 0xfe], 0x1000);
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x04],             // TNT N
+        ]);
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .images(vec![image])
+            .build()
+            .unwrap();
+        assert_eq!(
+            decoder.coverage(&trace, &mut [0u8; 16]),
+            Err(PtDecoderError::InfiniteLoop(0x1000))
+        );
+    }
+
+    #[cfg(feature = "tsc")]
+    #[test]
+    fn psbplus_tsc() {
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &[0x19, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22], // TSC
+            &MODE_EXEC_64,
+            &PSBEND,
+        ]);
+        let mut decoder = PtCoverageDecoderBuilder::new().build().unwrap();
+        assert_eq!(decoder.current_tsc(), None);
+        assert_eq!(decoder.coverage(&trace, &mut [0u8; 16]), Ok(()));
+        assert_eq!(decoder.current_tsc(), Some(0x0022_3344_5566_7788));
+    }
+
+    #[test]
+    fn flow_start_position() {
+        let mut decoder = sample_decoder();
+        assert_eq!(decoder.flow_start_position(), None);
+
+        let mut trace = vec![0x00; 3]; // Bytes preceding the sync point
+        trace.extend(sample_trace());
+        decoder.coverage(&trace, &mut [0u8; 64]).unwrap();
+        assert_eq!(
+            decoder.flow_start_position(),
+            Some(3 + Psb::SIZE + MODE_EXEC_64.len() + PSBEND.len())
+        );
+    }
+
+    #[cfg(feature = "retc")]
+    #[test]
+    fn ret_comp_stack_depth() {
+        let mut state = ExecutionState::new(AddressingMode::_64);
+        for ip in 0..100 {
+            state.push_ret(ip, 64);
+        }
+        assert_eq!(state.ret_comp_stack.len(), 64);
+        assert_eq!(state.ret_comp_stack.front(), Some(&36));
+        assert_eq!(state.ret_comp_stack.back(), Some(&99));
+
+        let mut state = ExecutionState::new(AddressingMode::_64);
+        state.push_ret(0x1000, 0);
+        assert!(state.ret_comp_stack.is_empty());
+
+        // 0x1000: call 0x1010
+        // 0x1005: jmp rax
+        // ...
+        // 0x1010: ret
+        let mut data = vec![0x90; 0x11];
+        data[..7].copy_from_slice(&[0xe8, 0x0b, 0x00, 0x00, 0x00, 0xff, 0xe0]);
+        data[0x10] = 0xc3;
+        let image = PtImage::new(data, 0x1000);
+        let ret_trace = |tnt| {
+            trace(&[
+                &Psb::CONTENT,
+                &MODE_EXEC_64,
+                &PSBEND,
+                &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+                &[tnt],              // TNT, the compressed ret
+            ])
+        };
+        let builder = PtCoverageDecoderBuilder::new().images(vec![image]);
+
+        let mut decoder = builder.clone().build().unwrap();
+        assert_eq!(decoder.coverage(&ret_trace(0x06), &mut [0u8; 16]), Ok(()));
+        assert_eq!(decoder.state.ip, 0x1005);
+
+        let mut decoder = builder.clone().build().unwrap();
+        assert_eq!(
+            decoder.coverage(&ret_trace(0x04), &mut [0u8; 16]),
+            Err(PtDecoderError::IncoherentImage)
+        );
+
+        // The return address was dropped
+        let mut decoder = builder.ret_comp_stack_depth(0).build().unwrap();
+        assert_eq!(
+            decoder.coverage(&ret_trace(0x06), &mut [0u8; 16]),
+            Err(PtDecoderError::EmptyReturnStack)
+        );
+    }
+
+    #[test]
+    fn coverage_between_chunks() {
+        let builder = sample_builder();
+        let trace = sample_trace();
+        let mut expected = [0u8; 64];
+        builder
+            .clone()
+            .build()
+            .unwrap()
+            .coverage(&trace, &mut expected)
+            .unwrap();
+
+        // Split after the TIP to the callee
+        let split = trace
+            .windows(SAMPLE_TIP_CALLEE.len())
+            .position(|w| w == SAMPLE_TIP_CALLEE)
+            .unwrap()
+            + SAMPLE_TIP_CALLEE.len();
+        let mut decoder = builder.build().unwrap();
+        let mut coverage = [0u8; 64];
+        decoder.coverage(&trace[..split], &mut coverage).unwrap();
+        let partial = coverage.iter().filter(|&&e| e != 0).count();
+        assert!(partial > 0);
+
+        decoder.coverage(&trace[split..], &mut coverage).unwrap();
+        assert!(coverage.iter().filter(|&&e| e != 0).count() > partial);
+        assert_eq!(coverage, expected);
+    }
+
+    #[test]
+    fn mov_cr3_in_tnt() {
+        // 0x1000: jne 0x1005
+        // 0x1002: mov cr3, rax
+        // 0x1005: je 0x1007
+        // 0x1007: ret
+        let image = PtImage::new(vec![0x75, 0x03, 0x0f, 0x22, 0xd8, 0x74, 0x00, 0xc3], 0x1000);
+        let pip_2000 = [0x02, 0x43, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00];
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &[0x02, 0x43, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00], // PIP 0x1000
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x0a],             // TNT N T
+            &pip_2000,
+            &[0x01], // TIP.PGD, IP suppressed
+        ]);
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .images(vec![image.clone()])
+            .build()
+            .unwrap();
+
+        let mut events = vec![];
+        assert_eq!(
+            decoder.coverage_with_events(&trace, &mut [0u8; 16], &mut events),
+            Ok(())
+        );
+        assert_eq!(
+            events,
+            [PtDecoderEvent::PagingChange {
+                old_cr3: 0x1000,
+                new_cr3: 0x2000,
+                non_root_vmx: false
+            }]
+        );
+
+        // The PIP must immediately follow
+        let pos = trace.windows(8).position(|w| w == pip_2000).unwrap();
+        let mut missing_pip = trace.clone();
+        missing_pip.drain(pos..pos + 8);
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .images(vec![image])
+            .build()
+            .unwrap();
+        assert!(matches!(
+            decoder.coverage(&missing_pip, &mut [0u8; 16]),
+            Err(PtDecoderError::InvalidPacketSequence { .. })
+        ));
+    }
+
+    #[test]
+    fn validate() {
+        let builder = sample_builder();
+        let trace = sample_trace();
+        assert_eq!(builder.clone().validate(&trace), Ok(()));
+
+        // Move the indirect call target out of the image
+        let mut corrupted = trace.clone();
+        let pos = corrupted
+            .windows(SAMPLE_TIP_CALLEE.len())
+            .position(|w| w == SAMPLE_TIP_CALLEE)
+            .unwrap();
+        corrupted[pos + 2] = 0x20;
+        assert_eq!(
+            builder.validate(&corrupted),
+            Err(PtDecoderError::MissingImage { address: 0x2020 })
+        );
+    }
+
+    #[test]
+    fn empty_coverage_map() {
+        assert_eq!(coverage_entry(0x1000, 0x2000, 0), None);
+        assert!(coverage_entry(0x1000, 0x2000, 1).is_some());
+
+        let mut decoder = sample_decoder();
+        let coverage: &mut [u8] = &mut [];
+        assert_eq!(
+            decoder.coverage(&sample_trace(), coverage),
+            Err(PtDecoderError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn cached_decoding_is_consistent() {
+        let builder = sample_builder();
+        let trace = sample_trace();
+
+        let mut warm = builder.clone().build().unwrap();
+        let mut warm_coverage = [0u32; 64];
+        let mut cold_coverage = [0u32; 64];
+        for _ in 0..16 {
+            warm.coverage(&trace, &mut warm_coverage).unwrap();
+            let mut cold = builder.clone().build().unwrap();
+            cold.coverage(&trace, &mut cold_coverage).unwrap();
+        }
+
+        assert_eq!(warm_coverage, cold_coverage);
+        assert_eq!(warm_coverage.iter().sum::<u32>(), 16 * 3);
+    }
+
+    #[test]
+    fn direct_jumps_reuse_inst_decoder() {
+        // 64 chained `jmp $+4` followed by a `jne` to itself and `ret`
+        let mut data = [0xeb, 0x02, 0x90, 0x90].repeat(64);
+        data.extend_from_slice(&[0x75, 0xfe, 0xc3]);
+        let image = PtImage::new(data, 0x1000);
+
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x06],             // TNT T
+            &[0x04],             // TNT N
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .images(vec![image])
+            .build()
+            .unwrap();
+        let mut coverage = [0u8; 64];
+        decoder.coverage(&trace, &mut coverage).unwrap();
+
+        assert_eq!(coverage.iter().map(|&c| c as u32).sum::<u32>(), 1);
+        // One build per instruction walk: TNT T, TNT N, TIP.PGD
+        assert!(decoder.inst_decoder_builds <= 3);
+    }
+
+    #[test]
+    fn ip_at_image_end_is_missing() {
+        let image = PtImage::new(vec![0x90, 0x90], 0x1000);
+        let trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x04],             // TNT N
+        ]);
+
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .images(vec![image])
+            .build()
+            .unwrap();
+        assert_eq!(
+            decoder.coverage(&trace, &mut [0u8; 16]),
+            Err(PtDecoderError::MissingImage { address: 0x1002 })
+        );
+    }
+
+    #[test]
+    fn missing_image_address() {
+        let images = vec![sample_image(), PtImage::new(vec![0x90; 0x10], 0x2000)];
+        let mut coverage = [0u8; 16];
+
+        // Between the two images
+        let gap_trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x18], // TIP.PGE 0x1800
+            &[0x04],             // TNT N
+        ]);
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .images(images.clone())
+            .build()
+            .unwrap();
+        assert_eq!(
+            decoder.coverage(&gap_trace, &mut coverage),
+            Err(PtDecoderError::MissingImage { address: 0x1800 })
+        );
+
+        // Past the last image, reached by an indirect branch
+        let tip_trace = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x04],             // TNT N
+            &[0x2d, 0x10, 0x20], // TIP 0x2010
+            &[0x04],             // TNT N
+        ]);
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .images(images)
+            .build()
+            .unwrap();
+        assert_eq!(
+            decoder.coverage(&tip_trace, &mut coverage),
+            Err(PtDecoderError::MissingImage { address: 0x2010 })
+        );
+    }
+
+    #[test]
+    fn image_too_large() {
+        let image = PtImage::new(vec![0x90; 0x20], u64::MAX - 0x10);
+        assert_eq!(
+            PtCoverageDecoderBuilder::new()
+                .images(vec![image])
+                .build()
+                .err(),
+            Some(PtDecoderError::ImageTooLarge)
+        );
+    }
+
+    #[test]
+    fn coverage_shorthand() {
+        let coverage = super::coverage(&sample_trace(), &[sample_image()], 64).unwrap();
+
+        assert_eq!(coverage.len(), 64);
+        assert_eq!(coverage.iter().filter(|&&c| c != 0).count(), 3);
+    }
+
+    #[test]
+    fn coverage_cell_types() {
+        let builder = sample_builder();
+        let trace = sample_trace();
+
+        let mut u32_coverage = [0u32; 64];
+        let mut atomic_coverage: [AtomicU8; 64] = std::array::from_fn(|_| AtomicU8::new(0));
+        let mut bool_coverage = [false; 64];
+        for _ in 0..2 {
+            let mut decoder = builder.clone().build().unwrap();
+            decoder.coverage(&trace, &mut u32_coverage).unwrap();
+            let mut decoder = builder.clone().build().unwrap();
+            decoder.coverage(&trace, &mut atomic_coverage).unwrap();
+            let mut decoder = builder.clone().build().unwrap();
+            decoder.coverage(&trace, &mut bool_coverage).unwrap();
+        }
+
+        for ((u, a), b) in u32_coverage.iter().zip(&atomic_coverage).zip(bool_coverage) {
+            assert_eq!(*u, a.load(Ordering::Relaxed) as u32);
+            assert_eq!(*u != 0, b);
+        }
+        assert_eq!(u32_coverage.iter().sum::<u32>(), 2 * 3);
+    }
+
+    #[test]
+    fn new_entries() {
+        let mut decoder = sample_decoder();
+        let trace = sample_trace();
+        let mut coverage = [0u8; 64];
+
+        let mut new_entries = vec![];
+        decoder
+            .coverage_with_new_entries(&trace, &mut coverage, &mut new_entries)
+            .unwrap();
+        assert_eq!(new_entries.len(), 3);
+        for &entry in &new_entries {
+            assert_eq!(coverage[entry], 1);
+        }
+
+        new_entries.clear();
+        decoder
+            .coverage_with_new_entries(&trace, &mut coverage, &mut new_entries)
+            .unwrap();
+        assert!(new_entries.is_empty());
+    }
+
+    #[test]
+    fn psb_preserves_state() {
+        let mid_stream = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+            &[0x04],             // TNT N
+            &Psb::CONTENT,
+            &[0x3d, 0x05, 0x10], // FUP 0x1005, without MODE.Exec
+            &PSBEND,
+            &SAMPLE_TIP_CALLEE,  // TIP 0x1020
+            &[0x2d, 0x07, 0x10], // TIP 0x1007
+            &[0x06],             // TNT T
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+
+        let mut decoder = sample_decoder();
+        let mut coverage = [0u8; 64];
+        decoder.coverage(&mid_stream, &mut coverage).unwrap();
+
+        let mut right = [0u8; 64];
+        let mut decoder = sample_decoder();
+        decoder.coverage(&sample_trace(), &mut right).unwrap();
+        assert_eq!(coverage, right);
+
+        // The sample image relocated above 4GiB: the 2 bytes TIPs only decode against the upper
+        // bytes of the last IP set by the TIP.PGE and by the PSB+ FUP
+        let base = 0x7fff_0000_0000;
+        let relocated = trace(&[
+            &Psb::CONTENT,
+            &MODE_EXEC_64,
+            &PSBEND,
+            &[0x71, 0x00, 0x10, 0x00, 0x00, 0xff, 0x7f], // TIP.PGE 0x7fff_0000_1000
+            &[0x04],                                     // TNT N
+            &SAMPLE_TIP_CALLEE,                          // TIP 0x7fff_0000_1020
+            &Psb::CONTENT,
+            &[0x7d, 0x20, 0x10, 0x00, 0x00, 0xff, 0x7f], // FUP 0x7fff_0000_1020
+            &PSBEND,
+            &[0x2d, 0x07, 0x10], // TIP 0x7fff_0000_1007
+            &[0x06],             // TNT T
+            &[0x01],             // TIP.PGD, IP suppressed
+        ]);
+        let image = PtImage::new(sample_image().data().to_vec(), base + 0x1000);
+        let mut decoder = PtCoverageDecoderBuilder::new()
+            .images(vec![image])
+            .build()
+            .unwrap();
+        let mut edges = vec![];
+        assert_eq!(
+            decoder.coverage_with_edges(&relocated, &mut coverage, &mut edges),
+            Ok(())
+        );
+        assert_eq!(
+            edges
+                .iter()
+                .map(|e| (e.from - base, e.to - base))
+                .collect::<Vec<_>>(),
+            [(0x1007, 0x1020), (0x1021, 0x1007), (0x1005, 0x1009)]
+        );
+
+        // The return address pushed before the PSB+ serves the compressed ret after it
+        #[cfg(feature = "retc")]
+        {
+            // 0x1000: call 0x1010
+            // 0x1005: jmp rax
+            // ...
+            // 0x1010: jne 0x1012
+            // 0x1012: ret
+            let mut data = vec![0x90; 0x13];
+            data[..7].copy_from_slice(&[0xe8, 0x0b, 0x00, 0x00, 0x00, 0xff, 0xe0]);
+            data[0x10..].copy_from_slice(&[0x75, 0x00, 0xc3]);
+            let compressed = trace(&[
+                &Psb::CONTENT,
+                &MODE_EXEC_64,
+                &PSBEND,
+                &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+                &[0x04],             // TNT N
+                &Psb::CONTENT,
+                &[0x3d, 0x12, 0x10], // FUP 0x1012
+                &PSBEND,
+                &[0x06], // TNT T, compressed ret
+                &[0x01], // TIP.PGD, IP suppressed
+            ]);
+            let mut decoder = PtCoverageDecoderBuilder::new()
+                .images(vec![PtImage::new(data, 0x1000)])
+                .build()
+                .unwrap();
+            let mut coverage = [0u8; 64];
+            let mut edges = vec![];
+            assert_eq!(
+                decoder.coverage_with_edges(&compressed, &mut coverage, &mut edges),
+                Ok(())
+            );
+            assert_eq!(
+                edges.iter().map(|e| (e.from, e.to)).collect::<Vec<_>>(),
+                [(0x1013, 0x1005)]
+            );
+        }
+    }
+
+    #[cfg(feature = "log_coverage")]
+    #[test]
+    fn coverage_collision_is_logged() {
+        use std::sync::Mutex;
+
+        struct CaptureLogger(Mutex<Vec<String>>);
+
+        impl log::Log for CaptureLogger {
+            fn enabled(&self, metadata: &log::Metadata) -> bool {
+                metadata.level() <= log::Level::Debug
+            }
+
+            fn log(&self, record: &log::Record) {
+                if self.enabled(record.metadata()) {
+                    self.0.lock().unwrap().push(record.args().to_string());
+                }
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: CaptureLogger = CaptureLogger(Mutex::new(Vec::new()));
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let mut decoder = sample_decoder();
+        let mut coverage = [0u8; 1];
+        decoder.coverage(&sample_trace(), &mut coverage).unwrap();
+
+        assert_eq!(coverage[0], 3);
+        assert!(
+            LOGGER
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|l| l.starts_with("Coverage entry 0 collision"))
+        );
+    }
 }
@@ -0,0 +1,42 @@
+//! Entry points for the `fuzz/` targets, built with `--cfg fuzzing`.
+//!
+//! Decoding untrusted traces must never panic: every input either decodes or returns an `Err`.
+
+use crate::packet::decoder::PtPacketDecoder;
+use crate::{PtCoverageDecoderBuilder, PtImage};
+
+/// Virtual address of the image built by [`coverage_decoder`]
+pub const IMAGE_ADDRESS: u64 = 0x1000;
+
+/// Iterates over all the packets of `data`
+pub fn packet_decoder(data: &[u8]) {
+    let _ = PtPacketDecoder::new(data).map(|d| d.count());
+}
+
+/// Computes the coverage of a trace over a small image, both taken from `data`.
+///
+/// The first byte is the image length, the image content follows and the rest is the trace.
+pub fn coverage_decoder(data: &[u8]) {
+    let Some((&image_len, data)) = data.split_first() else {
+        return;
+    };
+    let (image, trace) = data.split_at(data.len().min(image_len as usize));
+    let mut coverage = [0u8; 64];
+
+    let _ = PtCoverageDecoderBuilder::new()
+        .images(vec![PtImage::new(image.to_vec(), IMAGE_ADDRESS)])
+        .build()
+        .and_then(|mut d| d.coverage(trace, &mut coverage));
+}
+
+/// Builds the images of `data` parsed as an ELF file
+#[cfg(feature = "elf")]
+pub fn from_elf(data: &[u8]) {
+    let _ = PtImage::from_elf(data, 0);
+}
+
+/// Builds the images of `data` parsed as a PE file
+#[cfg(feature = "pe")]
+pub fn from_pe(data: &[u8]) {
+    let _ = PtImage::from_pe(data, 0);
+}
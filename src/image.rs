@@ -1,5 +1,37 @@
+#[cfg(any(feature = "elf", feature = "pe"))]
+use object::{Object, ObjectSegment};
+use std::hash::{Hash, Hasher};
+
+#[cfg(feature = "elf")]
+mod elf;
+#[cfg(feature = "pe")]
+mod pe;
+
+/// Largest executable segment accepted by [`PtImage::from_elf`] and [`PtImage::from_pe`], the
+/// memory size comes from the file and is not trusted
+#[cfg(any(feature = "elf", feature = "pe"))]
+const MAX_SEGMENT_SIZE: u64 = 1 << 30;
+
+/// Error building images from an executable file
+#[cfg(any(feature = "elf", feature = "pe"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PtImageError {
+    /// The file could not be parsed
+    Object(object::Error),
+    /// An executable segment is larger in memory than 1 GiB
+    SegmentTooLarge { address: u64, size: u64 },
+}
+
+#[cfg(any(feature = "elf", feature = "pe"))]
+impl From<object::Error> for PtImageError {
+    fn from(error: object::Error) -> Self {
+        Self::Object(error)
+    }
+}
+
 // todo better debug print
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PtImage {
     data: Vec<u8>,
     virtual_address: u64,
@@ -17,6 +49,18 @@ impl PtImage {
         }
     }
 
+    /// Restrict the image to the address space identified by the `cr3` value
+    pub const fn with_cr3(mut self, cr3: u64) -> Self {
+        self.cr3 = Some(cr3);
+        self
+    }
+
+    /// Restrict the image to the virtual machine identified by the `vmcs_ptr` value
+    pub const fn with_vmcs(mut self, vmcs_ptr: u64) -> Self {
+        self.vmcs_ptr = Some(vmcs_ptr);
+        self
+    }
+
     pub const fn data(&self) -> &[u8] {
         self.data.as_slice()
     }
@@ -25,8 +69,34 @@ impl PtImage {
         self.virtual_address
     }
 
+    /// Returns the address following the last byte of the image, saturated at `u64::MAX`
     pub const fn virtual_address_end(&self) -> u64 {
-        self.virtual_address + self.data.len() as u64
+        self.virtual_address.saturating_add(self.data.len() as u64)
+    }
+
+    /// Returns true if `ip` is in the image address range.
+    ///
+    /// The range is half-open: `[virtual_address_start(), virtual_address_end())`.
+    pub const fn contains(&self, ip: u64) -> bool {
+        ip >= self.virtual_address_start() && ip < self.virtual_address_end()
+    }
+
+    /// Returns a copy of the `[start, end)` address range of the image, None if the range is not
+    /// fully contained in the image.
+    ///
+    /// The CR3 and VMCS restrictions are preserved.
+    pub fn slice(&self, start: u64, end: u64) -> Option<PtImage> {
+        if start > end || start < self.virtual_address_start() || end > self.virtual_address_end() {
+            return None;
+        }
+
+        let offset = usize::try_from(start - self.virtual_address).ok()?;
+        let len = usize::try_from(end - start).ok()?;
+        Some(Self {
+            data: self.data.get(offset..offset + len)?.to_vec(),
+            virtual_address: start,
+            ..*self
+        })
     }
 
     pub const fn cr3(&self) -> Option<u64> {
@@ -36,4 +106,99 @@ impl PtImage {
     pub const fn vmcs_ptr(&self) -> Option<u64> {
         self.vmcs_ptr
     }
+
+    /// Images of the executable segments of `file`, at their relative address plus `base`
+    #[cfg(any(feature = "elf", feature = "pe"))]
+    fn from_object<'data>(file: &impl Object<'data>, base: u64) -> Result<Vec<Self>, PtImageError> {
+        file.segments()
+            .filter(|segment| segment.permissions().executable())
+            .map(|segment| {
+                let address = segment
+                    .address()
+                    .wrapping_sub(file.relative_address_base())
+                    .wrapping_add(base);
+                let size = segment.size();
+                if size > MAX_SEGMENT_SIZE {
+                    return Err(PtImageError::SegmentTooLarge { address, size });
+                }
+
+                let mut data = segment.data()?.to_vec();
+                let size = size as usize;
+                if size < data.len() {
+                    // The file data is padded to the file alignment, e.g. in PE files
+                    data.truncate(size);
+                } else {
+                    data.resize(size, 0);
+                }
+                Ok(Self::new(data, address))
+            })
+            .collect()
+    }
+}
+
+/// For speed, only the data length is hashed, not the data itself. Images mapping different
+/// data at the same address range therefore collide, but are still told apart by [`Eq`].
+impl Hash for PtImage {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.virtual_address.hash(state);
+        self.data.len().hash(state);
+        self.cr3.hash(state);
+        self.vmcs_ptr.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn dedup() {
+        let images = [
+            PtImage::new(vec![0x90, 0xc3], 0x1000),
+            PtImage::new(vec![0x90, 0xc3], 0x1000),
+            PtImage::new(vec![0xcc, 0xc3], 0x1000),
+            PtImage::new(vec![0x90, 0xc3], 0x2000),
+        ];
+
+        let set = images.into_iter().collect::<HashSet<_>>();
+        assert_eq!(set.len(), 3);
+    }
+
+    #[test]
+    fn contains() {
+        let image = PtImage::new(vec![0x90, 0x90, 0xc3], 0x1000);
+
+        assert!(!image.contains(0xfff));
+        assert!(image.contains(image.virtual_address_start()));
+        assert!(image.contains(0x1002));
+        assert!(!image.contains(image.virtual_address_end()));
+    }
+
+    #[test]
+    fn with_cr3_and_vmcs() {
+        let image = PtImage::new(vec![0xc3], 0x1000);
+        assert_eq!(image.cr3(), None);
+        assert_eq!(image.vmcs_ptr(), None);
+
+        let image = image.with_cr3(0x1234_5000).with_vmcs(0xabc_d000);
+        assert_eq!(image.cr3(), Some(0x1234_5000));
+        assert_eq!(image.vmcs_ptr(), Some(0xabc_d000));
+    }
+
+    #[test]
+    fn slice() {
+        let image = PtImage::new(vec![0x90, 0x90, 0x31, 0xc0, 0xc3], 0x1000).with_cr3(0x2000);
+
+        let sliced = image.slice(0x1002, 0x1005).unwrap();
+        assert_eq!(sliced.virtual_address_start(), 0x1002);
+        assert_eq!(sliced.virtual_address_end(), 0x1005);
+        assert_eq!(sliced.data(), [0x31, 0xc0, 0xc3]);
+        assert_eq!(sliced.cr3(), Some(0x2000));
+
+        assert_eq!(image.slice(0x1000, 0x1005), Some(image.clone()));
+        assert_eq!(image.slice(0xfff, 0x1002), None);
+        assert_eq!(image.slice(0x1002, 0x1006), None);
+        assert_eq!(image.slice(0x1003, 0x1002), None);
+    }
 }
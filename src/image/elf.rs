@@ -0,0 +1,122 @@
+use crate::PtImage;
+use crate::image::PtImageError;
+use object::read::elf::{ElfFile32, ElfFile64};
+use object::{Endianness, FileKind};
+
+impl PtImage {
+    /// Images of the executable `PT_LOAD` segments of the ELF file `bytes`, loaded at
+    /// `p_vaddr + load_bias`.
+    ///
+    /// `load_bias` is 0 for non-PIE executables, the load address otherwise (e.g. the start of the
+    /// first mapping of the file in `/proc/<pid>/maps`). Segments larger in memory than in the
+    /// file are zero padded.
+    pub fn from_elf(bytes: &[u8], load_bias: u64) -> Result<Vec<Self>, PtImageError> {
+        if FileKind::parse(bytes)? == FileKind::Elf32 {
+            Self::from_object(&ElfFile32::<Endianness>::parse(bytes)?, load_bias)
+        } else {
+            // Fails if the file is not an ELF
+            Self::from_object(&ElfFile64::<Endianness>::parse(bytes)?, load_bias)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object::{Object, ObjectSection};
+
+    const TEXT_ADDRESS: u64 = 0x40_10b0;
+
+    /// Static x86-64 executable with a read-only `PT_LOAD` segment for the headers and an
+    /// executable one for `.text`, 16 bytes larger in memory than in the file.
+    fn tiny_elf() -> Vec<u8> {
+        let text = [0x90, 0x90, 0x90, 0xc3];
+        let shstrtab = b"\0.text\0.shstrtab\0";
+        let (phoff, text_offset, shstrtab_offset, shoff) = (0x40u64, 0xb0u64, 0xb4u64, 0xc8u64);
+
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        elf.extend(2u16.to_le_bytes()); // e_type: EXEC
+        elf.extend(0x3eu16.to_le_bytes()); // e_machine: x86-64
+        elf.extend(1u32.to_le_bytes());
+        elf.extend(TEXT_ADDRESS.to_le_bytes());
+        elf.extend(phoff.to_le_bytes());
+        elf.extend(shoff.to_le_bytes());
+        elf.extend(0u32.to_le_bytes());
+        for half in [64u16, 56, 2, 64, 3, 2] {
+            elf.extend(half.to_le_bytes());
+        }
+
+        let load = |flags: u32, offset: u64, file_size: u64, memory_size: u64| {
+            let mut phdr = 1u32.to_le_bytes().to_vec(); // PT_LOAD
+            phdr.extend(flags.to_le_bytes());
+            let vaddr = 0x40_0000 + offset + if flags & 1 != 0 { 0x1000 } else { 0 };
+            for word in [offset, vaddr, vaddr, file_size, memory_size, 0x1000] {
+                phdr.extend(word.to_le_bytes());
+            }
+            phdr
+        };
+        elf.extend(load(4, 0, text_offset, text_offset)); // R
+        elf.extend(load(5, text_offset, 4, 4 + 0x10)); // R + X
+
+        elf.extend(text);
+        elf.extend(shstrtab);
+        elf.resize(shoff as usize, 0);
+
+        let section = |name: u32, kind: u32, flags: u64, address: u64, offset: u64, size: u64| {
+            let mut shdr = name.to_le_bytes().to_vec();
+            shdr.extend(kind.to_le_bytes());
+            for word in [flags, address, offset, size] {
+                shdr.extend(word.to_le_bytes());
+            }
+            shdr.extend([0; 24]);
+            shdr
+        };
+        elf.extend([0; 64]);
+        elf.extend(section(1, 1, 0x6, TEXT_ADDRESS, text_offset, 4)); // .text, ALLOC + EXEC
+        elf.extend(section(7, 3, 0, 0, shstrtab_offset, shstrtab.len() as u64));
+        elf
+    }
+
+    #[test]
+    fn from_elf() {
+        let elf = tiny_elf();
+        let images = PtImage::from_elf(&elf, 0).unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].virtual_address_start(), TEXT_ADDRESS);
+        // Zero padded to the size in memory
+        assert_eq!(images[0].data().len(), 4 + 0x10);
+        assert_eq!(images[0].data()[..4], [0x90, 0x90, 0x90, 0xc3]);
+        assert!(images[0].data()[4..].iter().all(|&b| b == 0));
+
+        let file = object::File::parse(elf.as_slice()).unwrap();
+        let text = file.section_by_name(".text").unwrap();
+        let bias = 0x7f00_0000_0000;
+        let images = PtImage::from_elf(&elf, bias).unwrap();
+        assert!(images[0].contains(bias + text.address()));
+        assert!(images[0].contains(bias + text.address() + text.size() - 1));
+        assert_eq!(
+            &images[0].data()[..text.size() as usize],
+            text.data().unwrap()
+        );
+    }
+
+    #[test]
+    fn segment_too_large() {
+        let mut elf = tiny_elf();
+        // p_memsz of the executable PT_LOAD, the second program header
+        let memsz = 0x40 + 56 + 40;
+        elf[memsz..memsz + 8].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        assert_eq!(
+            PtImage::from_elf(&elf, 0),
+            Err(PtImageError::SegmentTooLarge {
+                address: TEXT_ADDRESS,
+                size: 1 << 40
+            })
+        );
+    }
+
+    #[test]
+    fn not_an_elf() {
+        assert!(PtImage::from_elf(&[0x90; 64], 0).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use crate::PtImage;
+use crate::image::PtImageError;
+use object::FileKind;
+use object::read::pe::{PeFile32, PeFile64};
+
+impl PtImage {
+    /// Images of the executable sections of the PE file `bytes`, loaded at
+    /// `image_base + VirtualAddress`.
+    ///
+    /// `image_base` is the address the module is loaded at, it differs from the preferred one
+    /// stored in the file when the module is relocated (e.g. by ASLR). Sections larger in memory
+    /// (`VirtualSize`) than in the file (`SizeOfRawData`) are zero padded, the file alignment
+    /// padding is trimmed.
+    pub fn from_pe(bytes: &[u8], image_base: u64) -> Result<Vec<Self>, PtImageError> {
+        if FileKind::parse(bytes)? == FileKind::Pe32 {
+            Self::from_object(&PeFile32::parse(bytes)?, image_base)
+        } else {
+            // Fails if the file is not a PE
+            Self::from_object(&PeFile64::parse(bytes)?, image_base)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: [u8; 4] = [0x90, 0x90, 0x90, 0xc3];
+
+    /// x86-64 PE with an executable `.text` section, 8 bytes larger in memory than in the file,
+    /// and a `.data` section.
+    fn tiny_pe() -> Vec<u8> {
+        let mut pe = vec![0; 0x600];
+        pe[..2].copy_from_slice(b"MZ");
+        pe[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes()); // e_lfanew
+
+        let mut nt = b"PE\0\0".to_vec();
+        // COFF header: machine, sections, timestamp, symbols, optional header size, flags
+        nt.extend(0x8664u16.to_le_bytes());
+        nt.extend(2u16.to_le_bytes());
+        nt.extend([0; 12]);
+        nt.extend(240u16.to_le_bytes());
+        nt.extend(0x22u16.to_le_bytes());
+
+        let mut optional = vec![0; 240];
+        optional[..2].copy_from_slice(&0x20bu16.to_le_bytes()); // PE32+
+        optional[24..32].copy_from_slice(&0x1_4000_0000u64.to_le_bytes()); // ImageBase
+        optional[32..36].copy_from_slice(&0x1000u32.to_le_bytes()); // SectionAlignment
+        optional[36..40].copy_from_slice(&0x200u32.to_le_bytes()); // FileAlignment
+        optional[56..60].copy_from_slice(&0x3000u32.to_le_bytes()); // SizeOfImage
+        optional[60..64].copy_from_slice(&0x200u32.to_le_bytes()); // SizeOfHeaders
+        optional[108..112].copy_from_slice(&16u32.to_le_bytes()); // NumberOfRvaAndSizes
+        nt.extend(optional);
+
+        let section = |name: &[u8; 8], virtual_size: u32, rva: u32, raw: u32, flags: u32| {
+            let mut header = name.to_vec();
+            // The last 0 covers the 16 bit relocations and line numbers counts
+            for field in [virtual_size, rva, 0x200, raw, 0, 0, 0, flags] {
+                header.extend(field.to_le_bytes());
+            }
+            header
+        };
+        nt.extend(section(b".text\0\0\0", 0x208, 0x1000, 0x200, 0x6000_0020)); // CODE, R + X
+        nt.extend(section(b".data\0\0\0", 0x10, 0x2000, 0x400, 0xc000_0040)); // DATA, R + W
+
+        pe[0x40..0x40 + nt.len()].copy_from_slice(&nt);
+        pe[0x200..0x204].copy_from_slice(&TEXT);
+        pe
+    }
+
+    #[test]
+    fn from_pe() {
+        let image_base = 0x7ff6_0000_0000;
+        let images = PtImage::from_pe(&tiny_pe(), image_base).unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].virtual_address_start(), image_base + 0x1000);
+        // Zero padded to VirtualSize
+        assert_eq!(images[0].data().len(), 0x208);
+        assert_eq!(images[0].data()[..4], TEXT);
+        assert!(images[0].data()[4..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn not_a_pe() {
+        assert!(PtImage::from_pe(&[0x90; 64], 0).is_err());
+    }
+}
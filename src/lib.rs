@@ -2,13 +2,27 @@
 #![allow(clippy::just_underscores_and_digits)]
 
 pub use coverage_decoder::{
-    CoverageEntry, PtCoverageDecoder, PtCoverageDecoderBuilder, PtDecoderError,
+    CoverageCell, CoverageEntry, DecoderScratch, Edge, InstructionClass, PtCoverageDecoder,
+    PtCoverageDecoderBuilder, PtCoverageSession, PtDecoderError, PtDecoderEvent, PtEdgeStepper,
+    Ring, StaticEdgeCount, Trigger, classify_from, coverage, coverage_hash, static_edge_count,
 };
 pub use cpu::{PtCpu, PtCpuVendor};
 pub use image::PtImage;
+#[cfg(any(feature = "elf", feature = "pe"))]
+pub use image::PtImageError;
+pub use packet::decoder::segments;
+pub use packet::mode::{AddressingMode, TransactionState};
+#[cfg(feature = "perf")]
+pub use perf::perf_aux_trace;
 
 mod coverage_decoder;
 mod cpu;
+#[cfg(fuzzing)]
+pub mod fuzz;
 mod image;
 mod packet;
+#[cfg(feature = "perf")]
+mod perf;
+#[cfg(test)]
+mod test_utils;
 mod utils;
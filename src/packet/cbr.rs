@@ -1,8 +1,8 @@
 use crate::packet::SizedPtPacket;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Cbr {
-    // core_bus_ratio: u8,
+    pub(super) core_bus_ratio: u8,
 }
 
 impl SizedPtPacket for Cbr {
@@ -15,17 +15,10 @@ impl Cbr {
     pub(crate) const B1: u8 = 0x03;
     pub(crate) const SIZE: usize = 4;
 
-    // pub const fn core_bus_ratio(&self) -> u8 {
-    //     self.core_bus_ratio
-    // }
-
-    // pub(super) fn try_from_payload(payload: &[u8]) -> Result<Self, PtPacketParseError> {
-    //     let &[core_bus_ratio, _] = payload
-    //         .get(0..2)
-    //         .ok_or(PtPacketParseError::MalformedPacket)?
-    //         .try_into()
-    //         .unwrap();
-
-    //     Ok(Self { core_bus_ratio })
-    // }
+    /// Ratio between the core clock and the bus clock, the core frequency is this ratio times the
+    /// bus frequency
+    #[cfg_attr(not(feature = "cbr"), expect(dead_code))]
+    pub const fn core_bus_ratio(&self) -> u8 {
+        self.core_bus_ratio
+    }
 }
@@ -1,10 +1,48 @@
 use crate::packet::SizedPtPacket;
 
 #[derive(Debug, PartialEq, Clone)]
-pub struct Cfe {}
+pub struct Cfe {
+    pub(super) raw: [u8; 2],
+}
 
 impl SizedPtPacket for Cfe {
     fn original_size(&self) -> usize {
-        todo!()
+        Self::SIZE
+    }
+}
+
+impl Cfe {
+    pub(crate) const SIZE: usize = 4;
+
+    /// IP bit, a FUP carrying the IP the event binds to follows
+    pub const fn ip(&self) -> bool {
+        self.raw[0] & 0x80 != 0
+    }
+
+    /// Type of the control flow event, e.g. interrupt or INIT
+    pub const fn cfe_type(&self) -> u8 {
+        self.raw[0] & 0x1f
+    }
+
+    /// Vector of the event, e.g. the interrupt vector
+    pub const fn vector(&self) -> u8 {
+        self.raw[1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields() {
+        let cfe = Cfe { raw: [0x81, 0x20] };
+        assert!(cfe.ip());
+        assert_eq!(cfe.cfe_type(), 0x01);
+        assert_eq!(cfe.vector(), 0x20);
+
+        let cfe = Cfe { raw: [0x05, 0x00] };
+        assert!(!cfe.ip());
+        assert_eq!(cfe.cfe_type(), 0x05);
     }
 }
@@ -24,28 +24,23 @@ impl Cyc {
     // }
 
     /// Caller must check the header
-    ///
-    /// Panics if input len is 0
     #[inline]
     pub(super) fn try_from_payload(input: &[u8]) -> Result<Self, PtPacketParseError> {
-        let mut raw = [input[0], 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let header = *input.first().ok_or(PtPacketParseError::Eof)?;
+        let mut raw = [header, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
         let mut len = 1;
 
-        if input[0] & 0x04 != 0 {
-            if input.len() < 2 {
+        // Exp bit of the header and of the following bytes
+        let mut has_next = header & 0x04 != 0;
+        while has_next {
+            if len == raw.len() {
                 return Err(PtPacketParseError::MalformedPacket);
             }
-            raw[1] = input[1];
-
-            while input[len] & 0x01 != 0 && len < raw.len() {
-                len += 1;
-                if input.len() <= len {
-                    return Err(PtPacketParseError::MalformedPacket);
-                }
-                raw[len] = input[len];
-            }
+            let byte = *input.get(len).ok_or(PtPacketParseError::Truncated)?;
+            raw[len] = byte;
+            has_next = byte & 0x01 != 0;
             len += 1;
-        };
+        }
 
         Ok(Self { raw, len })
     }
@@ -71,6 +66,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn truncated() {
+        assert_eq!(Cyc::try_from_payload(&[]), Err(PtPacketParseError::Eof));
+        assert_eq!(
+            Cyc::try_from_payload(&[0b111]),
+            Err(PtPacketParseError::Truncated)
+        );
+        assert_eq!(
+            Cyc::try_from_payload(&[0b111, 0b1]),
+            Err(PtPacketParseError::Truncated)
+        );
+    }
+
+    #[test]
+    fn max_len() {
+        let mut raw = [0b1u8; 16];
+        raw[0] = 0b111;
+        raw[14] = 0;
+        assert_eq!(Cyc::try_from_payload(&raw).unwrap().original_size(), 15);
+
+        raw[14] = 0b1;
+        assert_eq!(
+            Cyc::try_from_payload(&raw),
+            Err(PtPacketParseError::MalformedPacket)
+        );
+    }
+
     // #[test]
     // fn cycle_counter() {
     //     let raws = [
@@ -1,50 +1,123 @@
 use crate::PtDecoderError;
-use crate::packet::psb::first_psb_position;
-use crate::packet::{PtPacket, SizedPtPacket};
+use crate::packet::mode;
+use crate::packet::psb::{Psb, first_psb_position};
+use crate::packet::{PtPacket, PtPacketParseError, SizedPtPacket};
+use std::ops::Range;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PtPacketDecoder<'a> {
     buffer: &'a [u8],
     pos: usize,
+    last_packet_start: usize,
+    /// Start of the last PSB returned by `next_packet`
+    last_psb: Option<usize>,
+    skip_unknown_mode: bool,
 }
 
 impl<'a> PtPacketDecoder<'a> {
     pub const fn new_not_syncd(buffer: &'a [u8]) -> Self {
-        Self { buffer, pos: 0 }
+        Self {
+            buffer,
+            pos: 0,
+            last_packet_start: 0,
+            last_psb: None,
+            skip_unknown_mode: false,
+        }
     }
 
     pub fn new(buffer: &'a [u8]) -> Result<Self, PtDecoderError> {
         let sync = first_psb_position(buffer).ok_or(PtDecoderError::SyncFailed)?;
-        Ok(Self { buffer, pos: sync })
+        Ok(Self {
+            buffer,
+            pos: sync,
+            last_packet_start: sync,
+            last_psb: None,
+            skip_unknown_mode: false,
+        })
+    }
+
+    /// Skip MODE packets with an unknown leaf instead of returning an error
+    pub const fn skip_unknown_mode(mut self, skip_unknown_mode: bool) -> Self {
+        self.skip_unknown_mode = skip_unknown_mode;
+        self
     }
 
     pub fn next_packet(&mut self) -> Result<PtPacket, PtDecoderError> {
-        let p = PtPacket::parse(self.buffer, &mut self.pos)?;
+        let p = self.parse_next_packet()?;
+        if let PtPacket::Psb(..) = p {
+            // Padding preceding the PSB is not part of the packet range
+            self.last_psb = Some(self.last_packet_start);
+        }
 
         #[cfg(feature = "log_packets")]
-        log::trace!("PT packet: {p:x?}");
+        log::trace!("PT packet at {:x?}: {p:x?}", self.last_packet_range());
 
         Ok(p)
     }
 
-    pub fn rollback_one_packet(&mut self, packet: PtPacket) -> Result<(), PtDecoderError> {
-        let mut pos = self.pos.checked_sub(packet.original_size()).ok_or(
-            PtDecoderError::InvalidPacketSequence {
-                packets: vec![packet.clone()],
-            },
-        )?;
-        let parsed = PtPacket::parse(self.buffer, &mut pos)?;
-        if parsed == packet {
-            self.pos -= packet.original_size();
-            Ok(())
-        } else {
-            Err(PtDecoderError::InvalidPacketSequence {
-                packets: vec![packet],
-            })
-        }
+    /// Length of the whole trace, including the bytes preceding the sync point
+    pub const fn trace_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Byte range of the trace holding the last packet returned by [`Self::next_packet`]
+    ///
+    /// Padding and skipped packets preceding it are not included. The range is empty if no packet
+    /// was decoded yet.
+    pub fn last_packet_range(&self) -> Range<usize> {
+        self.last_packet_start..self.pos
+    }
+
+    /// Position of the first byte of the last PSB returned by [`Self::next_packet`], None if no
+    /// PSB was decoded yet
+    pub const fn last_sync_position(&self) -> Option<usize> {
+        self.last_psb
+    }
+
+    /// Bytes from the last PSB returned by [`Self::next_packet`] up to the next PSB or the end of
+    /// the trace, None if no PSB was decoded yet
+    pub fn last_sync_segment(&self) -> Option<&'a [u8]> {
+        let start = self.last_psb?;
+        let next = self
+            .buffer
+            .get(start + Psb::SIZE..)
+            .and_then(first_psb_position);
+        let end = next.map_or(self.buffer.len(), |next| start + Psb::SIZE + next);
+        Some(&self.buffer[start..end])
+    }
+
+    /// Un-read the last packet returned by [`Self::next_packet`], so that the next call returns it
+    /// again, at the same position. Only one packet can be pushed back.
+    pub const fn push_back(&mut self) {
+        self.pos = self.last_packet_start;
+    }
+
+    /// Decode the next packet without consuming it
+    pub fn peek_packet(&self) -> Result<PtPacket, PtDecoderError> {
+        self.clone().parse_next_packet()
+    }
+
+    fn parse_next_packet(&mut self) -> Result<PtPacket, PtDecoderError> {
+        let p = loop {
+            match PtPacket::parse(self.buffer, &mut self.pos) {
+                Err(PtPacketParseError::UnknownPacket {
+                    b0: mode::B0,
+                    b1: Some(_b1),
+                }) if self.skip_unknown_mode => {
+                    #[cfg(feature = "log_packets")]
+                    log::debug!("Skipping MODE packet with unknown leaf 0x{_b1:x}");
+                    self.pos += mode::SIZE;
+                }
+                p => break p?,
+            }
+        };
+        self.last_packet_start = self.pos - p.original_size();
+        Ok(p)
     }
 }
 
+/// Ends with None when the buffer ends at a packet boundary. A buffer ending in the middle of a
+/// packet yields [`PtDecoderError::TruncatedTrace`] instead, as the last item.
 impl Iterator for PtPacketDecoder<'_> {
     type Item = Result<PtPacket, PtDecoderError>;
 
@@ -52,14 +125,72 @@ impl Iterator for PtPacketDecoder<'_> {
         match self.next_packet() {
             Ok(p) => Some(Ok(p)),
             Err(PtDecoderError::Eof) => None,
-            Err(e) => Some(Err(e)),
+            Err(e) => {
+                // The packet cannot be skipped, end the iteration instead of repeating the error
+                self.pos = self.buffer.len();
+                Some(Err(e))
+            }
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // Each packet is at least one byte long
+        (0, Some(self.buffer.len().saturating_sub(self.pos)))
+    }
+}
+
+/// Split the packets of `trace` at each PSB, yielding the offset of the PSB starting each segment
+/// and the packets of the segment, the PSB included.
+///
+/// The bytes preceding the first PSB are skipped. The last segment ends with the trace, even if
+/// the trace does not end with a PSB. No segment is yielded after an error.
+pub fn segments(
+    trace: &[u8],
+) -> impl Iterator<Item = Result<(usize, Vec<PtPacket>), PtDecoderError>> + '_ {
+    let (mut decoder, mut sync_error) = match PtPacketDecoder::new(trace) {
+        Ok(decoder) => (Some(decoder), None),
+        Err(e) => (None, Some(e)),
+    };
+    let mut segment: Option<(usize, Vec<PtPacket>)> = None;
+
+    std::iter::from_fn(move || {
+        if let Some(e) = sync_error.take() {
+            return Some(Err(e));
+        }
+        let packet_decoder = decoder.as_mut()?;
+        loop {
+            match packet_decoder.next_packet() {
+                Ok(PtPacket::Psb(psb)) => {
+                    let start = packet_decoder.last_sync_position().unwrap_or_default();
+                    let next = (start, vec![PtPacket::Psb(psb)]);
+                    if let Some(segment) = segment.replace(next) {
+                        return Some(Ok(segment));
+                    }
+                }
+                Ok(packet) => {
+                    if let Some((_, packets)) = &mut segment {
+                        packets.push(packet);
+                    }
+                }
+                Err(PtDecoderError::Eof) => {
+                    decoder = None;
+                    return segment.take().map(Ok);
+                }
+                Err(e) => {
+                    decoder = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    })
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::PtDecoderError;
     use crate::packet::PtPacket;
+    #[cfg(feature = "cbr")]
+    use crate::packet::cbr::Cbr;
     use crate::packet::decoder::PtPacketDecoder;
     use crate::packet::mode::{AddressingMode, ModeExec};
     use crate::packet::psb::{Psb, PsbEnd};
@@ -67,6 +198,184 @@ mod tests {
     use crate::packet::tnt::TntShort;
     use std::iter::zip;
 
+    #[test]
+    fn unknown_packet() {
+        let mut trace = [Psb::CONTENT.as_slice(), &[0x02, 0x1b, 0x00]].concat();
+        let mut decoder = PtPacketDecoder::new(&trace).unwrap();
+        assert_eq!(decoder.next_packet(), Ok(PtPacket::Psb(Psb {})));
+        assert_eq!(
+            decoder.next_packet(),
+            Err(PtDecoderError::UnknownPacket {
+                b0: 0x02,
+                b1: Some(0x1b)
+            })
+        );
+
+        trace.truncate(Psb::SIZE + 1);
+        let mut decoder = PtPacketDecoder::new(&trace).unwrap();
+        decoder.next_packet().unwrap();
+        assert_eq!(decoder.next_packet(), Err(PtDecoderError::TruncatedTrace));
+    }
+
+    #[test]
+    fn peek_packet() {
+        let mut decoder = PtPacketDecoder::new(TRACE).unwrap();
+        decoder.next_packet().unwrap();
+        #[cfg(feature = "cbr")]
+        decoder.next_packet().unwrap();
+
+        let peeked = decoder.peek_packet();
+        assert_eq!(peeked, Ok(PtPacket::PsbEnd(PsbEnd {})));
+        assert_eq!(decoder.peek_packet(), peeked);
+        assert_eq!(decoder.next_packet(), peeked);
+        assert_eq!(
+            decoder.next_packet(),
+            Ok(PtPacket::ModeExec(ModeExec::new(
+                AddressingMode::_64,
+                false
+            )))
+        );
+    }
+
+    #[test]
+    fn segments() {
+        let trace = [
+            Psb::CONTENT.as_slice(),
+            &[0x02, 0x23],       // PSBEND
+            &[0x31, 0x00, 0x10], // TIP.PGE
+            &[0x06],             // TNT
+            Psb::CONTENT.as_slice(),
+            &[0x02, 0x23], // PSBEND
+            &[0x01],       // TIP.PGD
+        ]
+        .concat();
+
+        let segments: Vec<_> = super::segments(&trace).collect::<Result<_, _>>().unwrap();
+        assert_eq!(
+            segments
+                .iter()
+                .map(|(offset, packets)| (*offset, packets.len()))
+                .collect::<Vec<_>>(),
+            [(0, 4), (22, 3)]
+        );
+
+        let packets: Vec<_> = PtPacketDecoder::new(&trace)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            segments
+                .into_iter()
+                .flat_map(|(_, packets)| packets)
+                .collect::<Vec<_>>(),
+            packets
+        );
+
+        assert_eq!(
+            super::segments(&[0x00, 0x00]).collect::<Vec<_>>(),
+            [Err(PtDecoderError::SyncFailed)]
+        );
+    }
+
+    #[test]
+    fn last_sync_position() {
+        let trace = [
+            Psb::CONTENT.as_slice(),
+            &[0x02, 0x23], // PSBEND
+            &[0x00, 0x00], // PAD
+            Psb::CONTENT.as_slice(),
+        ]
+        .concat();
+        let mut decoder = PtPacketDecoder::new(&trace).unwrap();
+        assert_eq!(decoder.last_sync_position(), None);
+        decoder.next_packet().unwrap();
+        assert_eq!(decoder.last_sync_position(), Some(0));
+        decoder.next_packet().unwrap();
+        assert_eq!(decoder.last_sync_position(), Some(0));
+
+        assert_eq!(decoder.next_packet(), Ok(PtPacket::Psb(Psb {})));
+        assert_eq!(decoder.last_sync_position(), Some(Psb::SIZE + 4));
+        assert_eq!(decoder.last_packet_range().len(), Psb::SIZE);
+    }
+
+    #[test]
+    fn next_truncated() {
+        let trace = [
+            Psb::CONTENT.as_slice(),
+            &[0x02, 0x23],             // PSBEND
+            &[0x02, 0x43, 0x00, 0x10], // PIP, truncated
+        ]
+        .concat();
+        let decoder = PtPacketDecoder::new(&trace).unwrap();
+        assert_eq!(
+            decoder.collect::<Vec<_>>(),
+            [
+                Ok(PtPacket::Psb(Psb {})),
+                Ok(PtPacket::PsbEnd(PsbEnd {})),
+                Err(PtDecoderError::TruncatedTrace)
+            ]
+        );
+    }
+
+    #[test]
+    fn push_back() {
+        let mut decoder = PtPacketDecoder::new(TRACE).unwrap();
+        decoder.next_packet().unwrap();
+        let packet = decoder.next_packet();
+        let range = decoder.last_packet_range();
+
+        decoder.push_back();
+        assert_eq!(decoder.next_packet(), packet);
+        assert_eq!(decoder.last_packet_range(), range);
+
+        // A second push back does not rewind further
+        decoder.push_back();
+        decoder.push_back();
+        assert_eq!(decoder.next_packet(), packet);
+    }
+
+    #[test]
+    fn last_packet_range() {
+        let trace = [
+            Psb::CONTENT.as_slice(),
+            &[0x02, 0x23],       // PSBEND
+            &[0x31, 0x00, 0x10], // TIP.PGE
+            &[0x06],             // TNT
+            &[0x01],             // TIP.PGD
+        ]
+        .concat();
+        let mut decoder = PtPacketDecoder::new(&trace).unwrap();
+        assert_eq!(decoder.last_packet_range(), 0..0);
+        let ranges: Vec<_> = std::iter::from_fn(|| {
+            decoder.next_packet().ok()?;
+            Some(decoder.last_packet_range())
+        })
+        .collect();
+        assert_eq!(ranges, [0..16, 16..18, 18..21, 21..22, 22..23]);
+
+        // Padding and CBRs between the packets are not part of the ranges
+        let mut decoder = PtPacketDecoder::new(TRACE).unwrap();
+        let mut end = 0;
+        for _ in right() {
+            decoder.next_packet().unwrap();
+            let range = decoder.last_packet_range();
+            assert!(range.start >= end && range.start < range.end);
+            end = range.end;
+        }
+    }
+
+    #[test]
+    fn size_hint() {
+        let decoder = PtPacketDecoder::new(TRACE).unwrap();
+        let (_, upper) = decoder.size_hint();
+        assert_eq!(upper, Some(TRACE.len()));
+        assert!(decoder.count() <= TRACE.len());
+
+        let mut decoder = PtPacketDecoder::new(TRACE).unwrap();
+        decoder.next_packet().unwrap();
+        assert_eq!(decoder.size_hint(), (0, Some(TRACE.len() - Psb::SIZE)));
+    }
+
     #[test]
     fn next() {
         let decoder = PtPacketDecoder::new(TRACE).unwrap();
@@ -107,28 +416,34 @@ mod tests {
     fn right() -> Box<[PtPacket]> {
         Box::new([
             PtPacket::Psb(Psb {}),
+            #[cfg(feature = "cbr")]
+            PtPacket::Cbr(Cbr {
+                core_bus_ratio: 0x23,
+            }),
             PtPacket::PsbEnd(PsbEnd {}),
             PtPacket::ModeExec(ModeExec::new(AddressingMode::_64, false)),
             PtPacket::TipPge(TipPge::try_from_payload(&TRACE[0x1f..]).unwrap()),
-            PtPacket::TntShort(TntShort::new(&[false, false])),
+            PtPacket::TntShort(TntShort::from_bits(&[false, false]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x2f..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x38..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x3b..]).unwrap()),
-            PtPacket::TntShort(TntShort::new(&[false, true])),
+            PtPacket::TntShort(TntShort::from_bits(&[false, true]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x41..]).unwrap()),
-            PtPacket::TntShort(TntShort::new(&[true])),
+            PtPacket::TntShort(TntShort::from_bits(&[true]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x48..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x4b..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x50..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x53..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x58..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x5b..]).unwrap()),
-            PtPacket::TntShort(TntShort::new(&[true, true, true, false, false, true])),
+            PtPacket::TntShort(
+                TntShort::from_bits(&[true, true, true, false, false, true]).unwrap(),
+            ),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x61..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x68..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x70..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x78..]).unwrap()),
-            PtPacket::TntShort(TntShort::new(&[false, true])),
+            PtPacket::TntShort(TntShort::from_bits(&[false, true]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x80..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x88..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x90..]).unwrap()),
@@ -136,28 +451,28 @@ mod tests {
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x9d..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0xa0..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0xa8..]).unwrap()),
-            PtPacket::TntShort(TntShort::new(&[false, true, false, true])),
+            PtPacket::TntShort(TntShort::from_bits(&[false, true, false, true]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0xb0..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0xb8..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0xc0..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0xc5..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0xc8..]).unwrap()),
-            PtPacket::TntShort(TntShort::new(&[false, true, true, true])),
+            PtPacket::TntShort(TntShort::from_bits(&[false, true, true, true]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0xd0..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0xd7..]).unwrap()),
-            PtPacket::TntShort(TntShort::new(&[false, false])),
+            PtPacket::TntShort(TntShort::from_bits(&[false, false]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0xe7..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0xf7..]).unwrap()),
-            PtPacket::TntShort(TntShort::new(&[true])),
+            PtPacket::TntShort(TntShort::from_bits(&[true]).unwrap()),
             PtPacket::TipPgd(TipPgd::try_from_payload(&TRACE[0x101..]).unwrap()),
             PtPacket::TipPge(TipPge::try_from_payload(&TRACE[0x102..]).unwrap()),
-            PtPacket::TntShort(TntShort::new(&[false])),
+            PtPacket::TntShort(TntShort::from_bits(&[false]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x107..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x110..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x115..]).unwrap()),
-            PtPacket::TntShort(TntShort::new(&[true])),
+            PtPacket::TntShort(TntShort::from_bits(&[true]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x119..]).unwrap()),
-            PtPacket::TntShort(TntShort::new(&[false])),
+            PtPacket::TntShort(TntShort::from_bits(&[false]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x120..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x125..]).unwrap()),
             PtPacket::Tip(Tip::try_from_payload(&TRACE[0x128..]).unwrap()),
@@ -1,10 +1,28 @@
 use crate::packet::SizedPtPacket;
 
 #[derive(Debug, PartialEq, Clone)]
-pub struct Evd {}
+pub struct Evd {
+    pub(super) raw: [u8; 9],
+}
 
 impl SizedPtPacket for Evd {
     fn original_size(&self) -> usize {
-        todo!()
+        Self::SIZE
+    }
+}
+
+impl Evd {
+    pub(crate) const SIZE: usize = 11;
+
+    /// Type of the event data, e.g. the page fault linear address
+    pub const fn evd_type(&self) -> u8 {
+        self.raw[0] & 0x3f
+    }
+
+    /// Event data payload
+    pub fn payload(&self) -> u64 {
+        let mut payload = [0; 8];
+        payload.copy_from_slice(&self.raw[1..]);
+        u64::from_le_bytes(payload)
     }
 }
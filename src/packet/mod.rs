@@ -34,12 +34,12 @@ use crate::packet::pad::Pad;
 pub mod bbp;
 pub mod cbr;
 #[cfg(feature = "event")]
-mod cfe;
+pub mod cfe;
 #[cfg(feature = "cyc")]
 pub mod cyc;
 pub mod decoder;
 #[cfg(feature = "event")]
-mod evd;
+pub mod evd;
 pub mod mnt;
 pub mod mode;
 #[cfg(feature = "mtc")]
@@ -89,6 +89,9 @@ pub enum PtPacket {
     ModeExec(ModeExec),
     ModeTsx(ModeTsx),
     TraceStop(TraceStop),
+    /// Core:Bus Ratio (CBR) Packet
+    #[cfg(feature = "cbr")]
+    Cbr(Cbr),
     /// Timestamp Counter (TSC) Packet
     #[cfg(feature = "tsc")]
     Tsc(Tsc),
@@ -147,6 +150,13 @@ pub enum PtPacket {
 pub enum PtPacketParseError {
     Eof,
     MalformedPacket,
+    /// The buffer ends in the middle of a packet
+    Truncated,
+    /// The header does not match any known packet, `b1` is None if the trace ends after `b0`
+    UnknownPacket {
+        b0: u8,
+        b1: Option<u8>,
+    },
 }
 
 impl PtPacket {
@@ -181,6 +191,11 @@ impl PtPacket {
                 [0x02, Ovf::B1, ..] => Self::Ovf(Ovf {}),
                 [0x02, TraceStop::B1, ..] => Self::TraceStop(TraceStop {}),
                 [0x02, Psb::B1, ..] => Self::Psb(Psb {}),
+                #[cfg(feature = "cbr")]
+                [0x02, Cbr::B1, core_bus_ratio, _, ..] => Self::Cbr(Cbr {
+                    core_bus_ratio: *core_bus_ratio,
+                }),
+                #[cfg(not(feature = "cbr"))]
                 [0x02, Cbr::B1, _, _, ..] => {
                     // ignore CBRs
                     *pos += Cbr::SIZE;
@@ -211,11 +226,13 @@ impl PtPacket {
                 #[cfg(feature = "pwr")]
                 [0x02, 0xa2, ..] => Self::Pwrx(Pwrx {}),
                 #[cfg(feature = "event")]
-                [0x02, 0x13, ..] => Self::Cfe(Cfe {}),
+                [0x02, 0x13, b2, b3, ..] => Self::Cfe(Cfe { raw: [*b2, *b3] }),
                 #[cfg(feature = "event")]
-                [0x02, 0x53, ..] => Self::Evd(Evd {}),
+                [0x02, 0x53, b2, b3, b4, b5, b6, b7, b8, b9, b10, ..] => Self::Evd(Evd {
+                    raw: [*b2, *b3, *b4, *b5, *b6, *b7, *b8, *b9, *b10],
+                }),
                 #[cfg(feature = "ptw")]
-                [0x02, b1, ..] if b1 & 0x1f == 0x12 => Self::Ptw(Ptw {}),
+                [0x02, b1, ..] if b1 & 0x1f == 0x12 => Self::Ptw(Ptw::try_from_payload(*b1)?),
                 #[cfg(feature = "pebs")]
                 [0x02, 0x33 | 0xb3, ..] => Self::Bep(Bep {}),
                 #[cfg(feature = "pebs")]
@@ -230,11 +247,28 @@ impl PtPacket {
                 [0x02, 0xc3, 0x88, b3, b4, b5, b6, b7, b8, b9, b10, ..] => Self::Mnt(Mnt {
                     raw: [*b3, *b4, *b5, *b6, *b7, *b8, *b9, *b10],
                 }),
-                [_, ..] => return Err(PtPacketParseError::MalformedPacket),
+                // Fixed size packets truncated by the end of the buffer
+                [0x02, Vmcs::B1 | Pip::B1 | TntLong::B1 | Cbr::B1, ..]
+                | [0x02, 0xc3, 0x88, ..]
+                | [0xd9, ..] => return Err(PtPacketParseError::Truncated),
+                #[cfg(feature = "event")]
+                [0x02, 0x13 | 0x53, ..] => return Err(PtPacketParseError::Truncated),
+                // Two bytes headers truncated by the end of the buffer
+                [0x02 | mode::B0] => return Err(PtPacketParseError::Truncated),
+                [b0, rest @ ..] => {
+                    return Err(PtPacketParseError::UnknownPacket {
+                        b0: *b0,
+                        b1: rest.first().copied(),
+                    });
+                }
                 [] => return Err(PtPacketParseError::Eof),
             };
         };
-        *pos += packet.original_size();
+        // Some packets are matched by their header only, check that the whole packet is there
+        *pos = pos
+            .checked_add(packet.original_size())
+            .filter(|&end| end <= input.len())
+            .ok_or(PtPacketParseError::Truncated)?;
         Ok(packet)
     }
 }
@@ -252,6 +286,8 @@ impl SizedPtPacket for PtPacket {
             PtPacket::ModeExec(..) => mode::SIZE,
             PtPacket::ModeTsx(..) => mode::SIZE,
             PtPacket::TraceStop(..) => TraceStop::SIZE,
+            #[cfg(feature = "cbr")]
+            PtPacket::Cbr(inner) => inner.original_size(),
             #[cfg(feature = "tsc")]
             PtPacket::Tsc(inner) => inner.original_size(),
             #[cfg(feature = "mtc")]
@@ -289,3 +325,128 @@ impl SizedPtPacket for PtPacket {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One encoded packet for each packet type
+    fn packets() -> Vec<(&'static str, &'static [u8])> {
+        vec![
+            ("TNT short", &[0x06]),
+            (
+                "TNT long",
+                &[0x02, 0xa3, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ),
+            ("TIP suppressed", &[0x0d]),
+            ("TIP 16", &[0x2d, 0x00, 0x10]),
+            ("TIP 32", &[0x4d, 0x00, 0x10, 0x00, 0x00]),
+            ("TIP sext 48", &[0x6d, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00]),
+            ("TIP 48", &[0x8d, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00]),
+            (
+                "TIP 64",
+                &[0xcd, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ),
+            ("TIP.PGE", &[0x31, 0x00, 0x10]),
+            ("TIP.PGD", &[0x21, 0x00, 0x10]),
+            ("FUP", &[0x3d, 0x00, 0x10]),
+            ("PIP", &[0x02, 0x43, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00]),
+            ("MODE.Exec", &[0x99, 0x01]),
+            ("MODE.TSX", &[0x99, 0x21]),
+            ("TraceStop", &[0x02, 0x83]),
+            ("VMCS", &[0x02, 0xc8, 0x00, 0x10, 0x00, 0x00, 0x00]),
+            ("OVF", &[0x02, 0xf3]),
+            ("PSB", &Psb::CONTENT),
+            ("PSBEND", &[0x02, 0x23]),
+            ("MNT", &[0x02, 0xc3, 0x88, 0, 0, 0, 0, 0, 0, 0, 0]),
+            ("TRIG", &[0xd9, 0x00, 0x00]),
+            #[cfg(feature = "cbr")]
+            ("CBR", &[0x02, 0x03, 0x20, 0x00]),
+            #[cfg(feature = "tsc")]
+            ("TSC", &[0x19, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]),
+            #[cfg(feature = "mtc")]
+            ("MTC", &[0x59, 0x00]),
+            #[cfg(all(feature = "tsc", feature = "mtc"))]
+            ("TMA", &[0x02, 0x73, 0x00, 0x00, 0x00, 0x00, 0x00]),
+            #[cfg(feature = "cyc")]
+            ("CYC", &[0x07, 0x01, 0x00]),
+            #[cfg(feature = "ptw")]
+            ("PTW 32", &[0x02, 0x12, 0x00, 0x00, 0x00, 0x00]),
+            #[cfg(feature = "ptw")]
+            (
+                "PTW 64",
+                &[0x02, 0xb2, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ),
+            #[cfg(feature = "pwr")]
+            (
+                "MWAIT",
+                &[0x02, 0xc2, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ),
+            #[cfg(feature = "pwr")]
+            ("PWRE", &[0x02, 0x22, 0x00, 0x00]),
+            #[cfg(feature = "pwr")]
+            ("PWRX", &[0x02, 0xa2, 0x00, 0x00, 0x00, 0x00, 0x00]),
+            #[cfg(feature = "pwr")]
+            ("EXSTOP", &[0x02, 0x62]),
+            #[cfg(feature = "pwr")]
+            ("EXSTOP IP", &[0x02, 0xe2]),
+            #[cfg(feature = "pebs")]
+            ("BBP", &[0x02, 0x63, 0x00]),
+            #[cfg(feature = "pebs")]
+            ("BEP", &[0x02, 0x33]),
+            #[cfg(feature = "pebs")]
+            ("BEP IP", &[0x02, 0xb3]),
+            #[cfg(feature = "event")]
+            ("CFE", &[0x02, 0x13, 0x00, 0x00]),
+            #[cfg(feature = "event")]
+            ("EVD", &[0x02, 0x53, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+        ]
+    }
+
+    #[test]
+    fn original_size_matches_consumed_bytes() {
+        let packets = packets();
+        let trace = packets
+            .iter()
+            .flat_map(|(_, p)| p.iter().copied())
+            .collect::<Vec<_>>();
+
+        let mut pos = 0;
+        for (name, expected) in packets {
+            let start = pos;
+            let packet = PtPacket::parse(&trace, &mut pos).unwrap();
+            assert_eq!(pos - start, expected.len(), "{name}");
+            assert_eq!(packet.original_size(), expected.len(), "{name}");
+        }
+        assert_eq!(
+            PtPacket::parse(&trace, &mut pos),
+            Err(PtPacketParseError::Eof)
+        );
+    }
+
+    #[test]
+    fn truncated_packets() {
+        for (name, packet) in packets() {
+            let mut pos = 0;
+            assert!(PtPacket::parse(packet, &mut pos).is_ok(), "{name}");
+            assert_eq!(pos, packet.len(), "{name}");
+
+            // One byte packets cannot be truncated, the header of two bytes ones is ambiguous
+            if packet.len() > 2 {
+                let mut pos = 0;
+                assert_eq!(
+                    PtPacket::parse(&packet[..packet.len() - 1], &mut pos),
+                    Err(PtPacketParseError::Truncated),
+                    "{name}"
+                );
+            }
+        }
+
+        // CBRs are skipped
+        let mut pos = 0;
+        assert_eq!(
+            PtPacket::parse(&[0x02, 0x03, 0x00], &mut pos),
+            Err(PtPacketParseError::Truncated)
+        );
+    }
+}
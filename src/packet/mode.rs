@@ -1,5 +1,5 @@
 use crate::packet::{PtPacketParseError, SizedPtPacket};
-use std::fmt::{Debug, Formatter};
+use std::fmt::{Debug, Display, Formatter};
 
 pub(crate) const SIZE: usize = 2;
 pub(crate) const B0: u8 = 0x99;
@@ -15,14 +15,19 @@ pub struct ModeTsx {
     transaction_state: TransactionState,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+/// Addressing mode of the executing code, as carried by MODE.Exec
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum AddressingMode {
+    /// 16-bit mode
     _16 = 0b00,
+    /// 32-bit (compatibility or legacy protected) mode
     _32 = 0b10,
+    /// 64-bit mode
     _64 = 0b01,
 }
 
+/// TSX transaction state, as carried by MODE.TSX
 #[derive(Debug, PartialEq, Clone, Copy)]
 #[repr(u8)]
 pub enum TransactionState {
@@ -99,6 +104,7 @@ impl Debug for ModeExec {
     }
 }
 
+/// Bitness of the addressing mode, as expected by `iced_x86::Decoder`
 impl From<AddressingMode> for u32 {
     fn from(value: AddressingMode) -> Self {
         match value {
@@ -109,6 +115,24 @@ impl From<AddressingMode> for u32 {
     }
 }
 
+/// "16-bit", "32-bit" or "64-bit"
+impl Display for AddressingMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-bit", u32::from(*self))
+    }
+}
+
+/// "begin", "abort" or "commit"
+impl Display for TransactionState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Begin => "begin",
+            Self::Abort => "abort",
+            Self::Commit => "commit",
+        })
+    }
+}
+
 macro_rules! impl_mode_sized_pt_packet {
     ($($ty:ty),+ $(,)?) => {
         $(
@@ -121,3 +145,18 @@ macro_rules! impl_mode_sized_pt_packet {
     };
 }
 impl_mode_sized_pt_packet!(ModeExec, ModeTsx);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display() {
+        assert_eq!(AddressingMode::_16.to_string(), "16-bit");
+        assert_eq!(AddressingMode::_32.to_string(), "32-bit");
+        assert_eq!(AddressingMode::_64.to_string(), "64-bit");
+        assert_eq!(TransactionState::Begin.to_string(), "begin");
+        assert_eq!(TransactionState::Abort.to_string(), "abort");
+        assert_eq!(TransactionState::Commit.to_string(), "commit");
+    }
+}
@@ -13,10 +13,7 @@ impl SizedPtPacket for Mtc {
 
 impl Mtc {
     pub(crate) fn try_from_payload(payload: &[u8]) -> Result<Self, PtPacketParseError> {
-        if payload.is_empty() {
-            Err(PtPacketParseError::MalformedPacket)
-        } else {
-            Ok(Self { raw: payload[0] })
-        }
+        let raw = *payload.first().ok_or(PtPacketParseError::Truncated)?;
+        Ok(Self { raw })
     }
 }
@@ -14,14 +14,30 @@ impl SizedPtPacket for Pip {
 impl Pip {
     pub(crate) const SIZE: usize = 8;
     pub(crate) const B1: u8 = 0x43;
-    // pub const fn cr3(&self) -> u64 {
-    //     let o = &self.raw;
-    //     let extended = [o[0] & 0xfe, o[1], o[2], o[3], o[4], o[5], 0, 0];
-    //
-    //     u64::from_le_bytes(extended) << 5
-    // }
+
+    /// CR3 bits 51:5
+    pub const fn cr3(&self) -> u64 {
+        let o = &self.raw;
+        let extended = [o[0], o[1], o[2], o[3], o[4], o[5], 0, 0];
+
+        (u64::from_le_bytes(extended) >> 1) << 5
+    }
 
     pub const fn non_root_vmx(&self) -> bool {
         self.raw[0] & 0x01 != 0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cr3() {
+        let pip = Pip {
+            raw: [0xa3, 0x45, 0x23, 0x01, 0x00, 0x00],
+        };
+        assert_eq!(pip.cr3(), 0x1234_5a20);
+        assert!(pip.non_root_vmx());
+    }
+}
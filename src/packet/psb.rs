@@ -47,16 +47,31 @@ impl SizedPtPacket for PsbEnd {
     }
 }
 
-/// Returns the index of the first byte __after__ the first `Psb` packet
+/// Returns the index of the first byte of the first complete `Psb` packet
 pub(crate) fn first_psb_position(buffer: &[u8]) -> Option<usize> {
-    // todo: consider if optimize this
-    for (i, window) in buffer.windows(Psb::SIZE).enumerate() {
-        if window == Psb::CONTENT {
-            return Some(i);
-        }
+    let mut position = find_psb(buffer)?;
+
+    // A buffer starting in the middle of a PSB, immediately followed by a complete one, looks like
+    // a longer run of PSB byte pairs: the complete PSB is the last one of the run.
+    while buffer.get(position + Psb::SIZE..position + Psb::SIZE + 2) == Some(&[Psb::B0, Psb::B1]) {
+        position += 2;
     }
+    Some(position)
+}
 
-    None
+#[cfg(feature = "simd")]
+fn find_psb(buffer: &[u8]) -> Option<usize> {
+    memchr::memmem::find(buffer, &Psb::CONTENT)
+}
+
+#[cfg(not(feature = "simd"))]
+use find_psb_scalar as find_psb;
+
+#[cfg(any(not(feature = "simd"), test))]
+fn find_psb_scalar(buffer: &[u8]) -> Option<usize> {
+    buffer
+        .windows(Psb::SIZE)
+        .position(|window| window == Psb::CONTENT)
 }
 
 #[cfg(test)]
@@ -111,6 +126,51 @@ mod tests {
         }
     }
 
+    #[test]
+    fn first_psb_position_mid_psb() {
+        let psbend = [Psb::B0, PsbEnd::B1];
+        let trace = [
+            [0x82, 0x02, 0x82, 0x02, 0x82].as_slice(),
+            &Psb::CONTENT,
+            &psbend,
+        ]
+        .concat();
+        assert_eq!(first_psb_position(&trace), Some(5));
+
+        let trace = [&Psb::CONTENT[..6], &Psb::CONTENT, &psbend].concat();
+        assert_eq!(first_psb_position(&trace), Some(6));
+
+        // Incomplete PSB only
+        assert_eq!(first_psb_position(&Psb::CONTENT[1..]), None);
+    }
+
+    #[cfg(feature = "simd")]
+    #[test]
+    fn find_psb_simd_matches_scalar() {
+        use crate::utils::fmix64;
+
+        let mut seed = 0;
+        for len in [0, 15, 16, 17, 100, 4096] {
+            let mut buffer: Vec<u8> = (0..len)
+                .map(|_| {
+                    seed = fmix64(seed + 1);
+                    // Bias towards PSB bytes to get partial matches
+                    [Psb::B0, Psb::B1, seed as u8][seed as usize % 3]
+                })
+                .collect();
+            assert_eq!(find_psb(&buffer), find_psb_scalar(&buffer));
+
+            for _ in 0..3 {
+                if buffer.len() >= Psb::SIZE {
+                    seed = fmix64(seed + 1);
+                    let at = seed as usize % (buffer.len() - Psb::SIZE + 1);
+                    buffer[at..at + Psb::SIZE].copy_from_slice(&Psb::CONTENT);
+                }
+                assert_eq!(find_psb(&buffer), find_psb_scalar(&buffer));
+            }
+        }
+    }
+
     #[test]
     fn first_psb_position_empty_buffer() {
         let trace = &[];
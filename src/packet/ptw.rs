@@ -1,10 +1,24 @@
-use crate::packet::SizedPtPacket;
+use crate::packet::{PtPacketParseError, SizedPtPacket};
 
 #[derive(Debug, PartialEq, Clone)]
-pub struct Ptw {}
+pub struct Ptw {
+    payload_size: usize,
+}
 
 impl SizedPtPacket for Ptw {
     fn original_size(&self) -> usize {
-        todo!()
+        2 + self.payload_size
+    }
+}
+
+impl Ptw {
+    pub(super) const fn try_from_payload(b1: u8) -> Result<Self, PtPacketParseError> {
+        let payload_size = match (b1 >> 5) & 0x03 {
+            0b00 => 4,
+            0b01 => 8,
+            _ => return Err(PtPacketParseError::MalformedPacket), // Reserved PayloadBytes
+        };
+
+        Ok(Self { payload_size })
     }
 }
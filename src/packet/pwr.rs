@@ -5,33 +5,49 @@ pub struct Pwrx {}
 
 impl SizedPtPacket for Pwrx {
     fn original_size(&self) -> usize {
-        todo!()
+        Self::SIZE
     }
 }
 
+impl Pwrx {
+    pub(crate) const SIZE: usize = 7;
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Pwre {}
 
 impl SizedPtPacket for Pwre {
     fn original_size(&self) -> usize {
-        todo!()
+        Self::SIZE
     }
 }
 
+impl Pwre {
+    pub(crate) const SIZE: usize = 4;
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Mwait {}
 
 impl SizedPtPacket for Mwait {
     fn original_size(&self) -> usize {
-        todo!()
+        Self::SIZE
     }
 }
 
+impl Mwait {
+    pub(crate) const SIZE: usize = 10;
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Exstop {}
 
 impl SizedPtPacket for Exstop {
     fn original_size(&self) -> usize {
-        todo!()
+        Self::SIZE
     }
 }
+
+impl Exstop {
+    pub(crate) const SIZE: usize = 2;
+}
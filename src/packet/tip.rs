@@ -41,6 +41,12 @@ impl SizedPtPacket for Tip {
 impl Tip {
     const IPBYTES_MASK: u8 = 0b1110_0000;
 
+    /// Reconstruct the packet IP, using and updating `last_tip_ip`.
+    ///
+    /// Returns false if the IP is suppressed ([`IpBytes::None`]), in that case `last_tip_ip` is
+    /// left untouched, so that the next compressed IP is still reconstructed against the last
+    /// IP that was actually sent. Whether a suppressed IP is legit depends on the packet kind,
+    /// therefore it is up to the caller to decide if it is an error.
     pub const fn ip(&self, last_tip_ip: &mut u64) -> bool {
         *last_tip_ip = match self.ip_bytes {
             IpBytes::None => return false,
@@ -57,9 +63,29 @@ impl Tip {
         true
     }
 
-    /// Panics if input length is 0
+    /// Returns true if the packet does not carry an IP
+    pub const fn is_ip_suppressed(&self) -> bool {
+        matches!(self.ip_bytes, IpBytes::None)
+    }
+
+    /// Compression of the IP carried by the packet
+    pub const fn ip_bytes(&self) -> IpBytes {
+        self.ip_bytes
+    }
+
+    /// IP bytes as carried by the packet, not reconstructed against the last IP, see
+    /// [`Self::ip`]. Zero if the IP is suppressed.
+    pub const fn raw_target(&self) -> u64 {
+        self.target_ip
+    }
+
+    /// `payload` includes the header byte
     pub(super) fn try_from_payload(payload: &[u8]) -> Result<Self, PtPacketParseError> {
-        Ok(match (payload[0] & Tip::IPBYTES_MASK, &payload[1..]) {
+        let [header, payload @ ..] = payload else {
+            return Err(PtPacketParseError::Eof);
+        };
+
+        Ok(match (header & Tip::IPBYTES_MASK, payload) {
             (IpBytes::NONE, [..]) => Self {
                 ip_bytes: IpBytes::None,
                 target_ip: 0,
@@ -84,13 +110,19 @@ impl Tip {
                 ip_bytes: IpBytes::_64,
                 target_ip: u64::from_le_bytes([*b1, *b2, *b3, *b4, *b5, *b6, *b7, *b8]),
             },
-            _ => return Err(PtPacketParseError::MalformedPacket),
+            (
+                IpBytes::C16 | IpBytes::C32 | IpBytes::C48 | IpBytes::SIGN_EXTEND48 | IpBytes::C64,
+                _,
+            ) => return Err(PtPacketParseError::Truncated),
+            _ => return Err(PtPacketParseError::MalformedPacket), // Reserved IPBytes
         })
     }
 }
 
 impl IpBytes {
-    const fn original_size(&self) -> usize {
+    /// Size in bytes of a TIP, TIP.PGE, TIP.PGD or FUP packet with this IP compression,
+    /// including the header byte
+    pub const fn original_size(&self) -> usize {
         match self {
             IpBytes::None => 1,
             IpBytes::_16 => 3,
@@ -99,6 +131,11 @@ impl IpBytes {
             IpBytes::_64 => 9,
         }
     }
+
+    /// IPBytes field value, i.e. bits 7:5 of the packet header byte, in place
+    pub const fn header_bits(&self) -> u8 {
+        *self as u8
+    }
 }
 
 impl TryFrom<u8> for IpBytes {
@@ -116,3 +153,72 @@ impl TryFrom<u8> for IpBytes {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_payload() {
+        assert_eq!(Tip::try_from_payload(&[]), Err(PtPacketParseError::Eof));
+    }
+
+    #[test]
+    fn suppressed_ip_keeps_last_ip() {
+        let mut last_ip = 0xffff_8000_1234_5678;
+
+        let suppressed = Tip::try_from_payload(&[0x0d]).unwrap();
+        assert!(suppressed.is_ip_suppressed());
+        assert!(!suppressed.ip(&mut last_ip));
+        assert_eq!(last_ip, 0xffff_8000_1234_5678);
+
+        let tip_16 = Tip::try_from_payload(&[0x2d, 0xcd, 0xab]).unwrap();
+        assert!(!tip_16.is_ip_suppressed());
+        assert!(tip_16.ip(&mut last_ip));
+        assert_eq!(last_ip, 0xffff_8000_1234_abcd);
+    }
+
+    #[test]
+    fn ip_bytes_and_raw_target() {
+        let payload = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+        let cases = [
+            (0x0d, IpBytes::None, 0),
+            (0x2d, IpBytes::_16, 0x2211),
+            (0x4d, IpBytes::_32, 0x4433_2211),
+            (0x6d, IpBytes::SignExtend48, 0x6655_4433_2211),
+            (0x8d, IpBytes::_48, 0x6655_4433_2211),
+            (0xcd, IpBytes::_64, 0x8877_6655_4433_2211),
+        ];
+        for (header, ip_bytes, raw_target) in cases {
+            let tip = Tip::try_from_payload(&[&[header], payload.as_slice()].concat()).unwrap();
+            assert_eq!(tip.ip_bytes(), ip_bytes);
+            assert_eq!(tip.raw_target(), raw_target);
+        }
+    }
+
+    #[test]
+    fn ip_bytes_original_size() {
+        let variants = [
+            IpBytes::None,
+            IpBytes::_16,
+            IpBytes::_32,
+            IpBytes::SignExtend48,
+            IpBytes::_48,
+            IpBytes::_64,
+        ];
+        for ip_bytes in variants {
+            assert_eq!(IpBytes::try_from(ip_bytes.header_bits()), Ok(ip_bytes));
+
+            let mut packet = vec![0; ip_bytes.original_size()];
+            packet[0] = ip_bytes.header_bits() | 0x0d; // TIP
+            let tip = Tip::try_from_payload(&packet).unwrap();
+            assert_eq!(tip.original_size(), ip_bytes.original_size());
+            if ip_bytes != IpBytes::None {
+                assert_eq!(
+                    Tip::try_from_payload(&packet[..packet.len() - 1]),
+                    Err(PtPacketParseError::Truncated)
+                );
+            }
+        }
+    }
+}
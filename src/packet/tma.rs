@@ -14,16 +14,16 @@ impl SizedPtPacket for Tma {
 
 impl Tma {
     pub(super) fn try_from_payload(payload: &[u8]) -> Result<Self, PtPacketParseError> {
-        if payload.len() < 5 {
-            return Err(PtPacketParseError::MalformedPacket);
-        }
+        let [b0, b1, b2, b3, b4, ..] = *payload else {
+            return Err(PtPacketParseError::Truncated);
+        };
 
-        if payload[2] & 0x01 != 0 {
+        if b2 & 0x01 != 0 {
             return Err(PtPacketParseError::MalformedPacket);
         }
 
-        let ctc = u16::from_le_bytes([payload[0], payload[1]]);
-        let fast_counter = u16::from_le_bytes([payload[3], payload[4] & 0x01]);
+        let ctc = u16::from_le_bytes([b0, b1]);
+        let fast_counter = u16::from_le_bytes([b3, b4 & 0x01]);
 
         Ok(Self { ctc, fast_counter })
     }
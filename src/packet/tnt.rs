@@ -1,5 +1,7 @@
+use crate::PtDecoderError;
 use crate::packet::SizedPtPacket;
 use std::fmt::{Debug, Formatter};
+use std::iter::Rev;
 
 #[derive(Clone, PartialEq)]
 pub struct TntShort {
@@ -17,16 +19,20 @@ pub enum TntIter {
     TntLongIter(TntLongIter),
 }
 
+/// Iterates from the stop bit towards bit 1 with `mask`, and from bit 1 towards the stop bit with
+/// `back`: the bits left are the ones in between
 #[derive(Debug, PartialEq)]
 pub struct TntShortIter {
     tnt: TntShort,
     mask: u8,
+    back: u8,
 }
 
 #[derive(Debug, PartialEq)]
 pub struct TntLongIter {
     tnt: TntLong,
     mask: u64,
+    back: u64,
 }
 
 impl Debug for TntShort {
@@ -49,7 +55,12 @@ impl IntoIterator for TntShort {
 
     fn into_iter(self) -> Self::IntoIter {
         let mask = 1u8 << (7 - self.raw.leading_zeros());
-        Self::IntoIter { tnt: self, mask }
+        // Bit 0 is part of the opcode
+        Self::IntoIter {
+            tnt: self,
+            mask,
+            back: 0b10,
+        }
     }
 }
 
@@ -59,24 +70,37 @@ impl IntoIterator for TntLong {
 
     fn into_iter(self) -> Self::IntoIter {
         let mask = 1u64 << (63 - self.payload_as_u64().leading_zeros());
-        Self::IntoIter { tnt: self, mask }
+        Self::IntoIter {
+            tnt: self,
+            mask,
+            back: 1,
+        }
     }
 }
 
 impl TntShort {
     pub(super) const SIZE: usize = 1;
 
-    #[cfg(test)]
-    pub(super) fn new(taken_not_taken: &[bool]) -> Self {
-        assert!(!taken_not_taken.is_empty());
-        assert!(taken_not_taken.len() < 7);
+    /// Maximum number of taken/not-taken bits in a TNT short packet
+    pub const MAX_BITS: usize = 6;
 
-        let mut raw = 0b10 << taken_not_taken.len();
-        for (i, &is_taken) in taken_not_taken.iter().rev().enumerate() {
-            raw |= if is_taken { 0b10 << i } else { 0 };
+    /// Encode `taken_not_taken` (true if taken), in execution order.
+    ///
+    /// Returns [`PtDecoderError::InvalidArgument`] if the bits are empty or more than
+    /// [`Self::MAX_BITS`].
+    pub fn from_bits(taken_not_taken: &[bool]) -> Result<Self, PtDecoderError> {
+        if !(1..=Self::MAX_BITS).contains(&taken_not_taken.len()) {
+            return Err(PtDecoderError::InvalidArgument);
         }
 
-        Self { raw }
+        // Stop bit followed by the payload, bit 0 is always 0
+        let raw = (encode_bits(taken_not_taken) << 1) as u8;
+        Ok(Self { raw })
+    }
+
+    /// Taken/not-taken bits from the last executed branch to the first one
+    pub fn bits_rev(&self) -> Rev<TntShortIter> {
+        self.clone().into_iter().rev()
     }
 }
 
@@ -84,6 +108,27 @@ impl TntLong {
     pub(super) const SIZE: usize = 8;
     pub(super) const B1: u8 = 0xa3;
 
+    /// Maximum number of taken/not-taken bits in a TNT long packet
+    pub const MAX_BITS: usize = 47;
+
+    /// Encode `taken_not_taken` (true if taken), in execution order.
+    ///
+    /// Returns [`PtDecoderError::InvalidArgument`] if the bits are empty or more than
+    /// [`Self::MAX_BITS`].
+    pub fn from_bits(taken_not_taken: &[bool]) -> Result<Self, PtDecoderError> {
+        if !(1..=Self::MAX_BITS).contains(&taken_not_taken.len()) {
+            return Err(PtDecoderError::InvalidArgument);
+        }
+
+        let [raw @ .., _, _] = encode_bits(taken_not_taken).to_le_bytes();
+        Ok(Self { raw })
+    }
+
+    /// Taken/not-taken bits from the last executed branch to the first one
+    pub fn bits_rev(&self) -> Rev<TntLongIter> {
+        self.clone().into_iter().rev()
+    }
+
     const fn payload_as_u64(&self) -> u64 {
         u64::from_le_bytes([
             self.raw[0],
@@ -98,6 +143,13 @@ impl TntLong {
     }
 }
 
+/// Stop bit followed by `taken_not_taken`, the last bit is the least significant one
+fn encode_bits(taken_not_taken: &[bool]) -> u64 {
+    taken_not_taken
+        .iter()
+        .fold(1, |acc, &is_taken| (acc << 1) | u64::from(is_taken))
+}
+
 impl SizedPtPacket for TntShort {
     fn original_size(&self) -> usize {
         Self::SIZE
@@ -121,6 +173,15 @@ impl Iterator for TntIter {
     }
 }
 
+impl DoubleEndedIterator for TntIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        match self {
+            TntIter::TntLongIter(l) => l.next_back(),
+            TntIter::TntShortIter(s) => s.next_back(),
+        }
+    }
+}
+
 impl From<TntShortIter> for TntIter {
     fn from(value: TntShortIter) -> Self {
         Self::TntShortIter(value)
@@ -137,12 +198,22 @@ impl Iterator for TntShortIter {
     type Item = bool;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.mask >> 1 < self.back {
+            return None;
+        }
         self.mask >>= 1;
-        if self.mask <= 0b1 {
-            None
-        } else {
-            Some(self.tnt.raw & self.mask != 0)
+        Some(self.tnt.raw & self.mask != 0)
+    }
+}
+
+impl DoubleEndedIterator for TntShortIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.back >= self.mask {
+            return None;
         }
+        let bit = self.tnt.raw & self.back != 0;
+        self.back <<= 1;
+        Some(bit)
     }
 }
 
@@ -150,12 +221,22 @@ impl Iterator for TntLongIter {
     type Item = bool;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.mask >> 1 < self.back {
+            return None;
+        }
         self.mask >>= 1;
-        if self.mask == 0 {
-            None
-        } else {
-            Some(self.tnt.payload_as_u64() & self.mask != 0)
+        Some(self.tnt.payload_as_u64() & self.mask != 0)
+    }
+}
+
+impl DoubleEndedIterator for TntLongIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.back >= self.mask {
+            return None;
         }
+        let bit = self.tnt.payload_as_u64() & self.back != 0;
+        self.back <<= 1;
+        Some(bit)
     }
 }
 
@@ -189,7 +270,7 @@ mod tests {
         let raw = 0b00110100u8;
         let p = TntShort { raw };
 
-        let right = vec![true, false].repeat(2);
+        let right = [true, false].repeat(2);
 
         assert_eq!(p.into_iter().collect::<Vec<_>>(), right);
     }
@@ -199,10 +280,83 @@ mod tests {
         let raw = [0b10101010, 0b10101010, 0b10101010, 0b10101010, 0, 0];
         let p = TntLong { raw };
 
-        let mut right = vec![false, true].repeat(15);
+        let mut right = [false, true].repeat(15);
         right.push(false);
 
         assert_eq!(p.clone().into_iter().collect::<Vec<_>>().len(), right.len());
         assert_eq!(p.into_iter().collect::<Vec<_>>(), right);
     }
+
+    #[test]
+    fn from_bits_round_trip() {
+        let bits = [true, false, false, true, true, false];
+        for len in 1..=TntShort::MAX_BITS {
+            let tnt = TntShort::from_bits(&bits[..len]).unwrap();
+            assert_eq!(tnt.into_iter().collect::<Vec<_>>(), bits[..len]);
+        }
+        assert_eq!(TntShort::from_bits(&[true]), Ok(TntShort { raw: 0b110 }));
+
+        let bits: Vec<_> = (0..TntLong::MAX_BITS).map(|i| i % 3 == 0).collect();
+        for len in 1..=TntLong::MAX_BITS {
+            let tnt = TntLong::from_bits(&bits[..len]).unwrap();
+            assert_eq!(tnt.into_iter().collect::<Vec<_>>(), bits[..len]);
+        }
+    }
+
+    #[test]
+    fn bits_rev() {
+        let payloads: [&[bool]; 4] = [
+            &[true],
+            &[false],
+            &[true, false, false, true, true],
+            &[false, true, true, false, true, false],
+        ];
+        for bits in payloads {
+            let tnt = TntShort::from_bits(bits).unwrap();
+            let forward: Vec<_> = tnt.clone().into_iter().collect();
+            let rev: Vec<_> = tnt.bits_rev().collect();
+            assert_eq!(rev, forward.into_iter().rev().collect::<Vec<_>>());
+
+            let tnt = TntLong::from_bits(bits).unwrap();
+            let rev: Vec<_> = tnt.bits_rev().collect();
+            assert_eq!(rev, bits.iter().rev().copied().collect::<Vec<_>>());
+        }
+
+        let bits: Vec<_> = (0..TntLong::MAX_BITS).map(|i| i % 3 == 0).collect();
+        let tnt = TntLong::from_bits(&bits).unwrap();
+        assert_eq!(
+            tnt.bits_rev().collect::<Vec<_>>(),
+            bits.iter().rev().copied().collect::<Vec<_>>()
+        );
+
+        // Both ends meet without crossing the stop bit
+        let mut iter = TntShort::from_bits(&[true, false, true])
+            .unwrap()
+            .into_iter();
+        assert_eq!(iter.next(), Some(true));
+        assert_eq!(iter.next_back(), Some(true));
+        assert_eq!(iter.next_back(), Some(false));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+    }
+
+    #[test]
+    fn from_bits_length_limits() {
+        assert_eq!(
+            TntShort::from_bits(&[]),
+            Err(PtDecoderError::InvalidArgument)
+        );
+        assert_eq!(
+            TntShort::from_bits(&[false; TntShort::MAX_BITS + 1]),
+            Err(PtDecoderError::InvalidArgument)
+        );
+        assert_eq!(
+            TntLong::from_bits(&[]),
+            Err(PtDecoderError::InvalidArgument)
+        );
+        assert_eq!(
+            TntLong::from_bits(&[false; TntLong::MAX_BITS + 1]),
+            Err(PtDecoderError::InvalidArgument)
+        );
+    }
 }
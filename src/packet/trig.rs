@@ -13,4 +13,42 @@ impl SizedPtPacket for Trig {
 
 impl Trig {
     pub(crate) const SIZE: usize = 3;
+
+    /// Trigger Bit Vector (TRBV), one bit for each trigger that fired
+    pub const fn trigger_bits(&self) -> u8 {
+        self.raw[0] & 0x0f
+    }
+
+    /// MULT bit, a trigger fired multiple times since the last TRIG
+    pub const fn multiple(&self) -> bool {
+        self.raw[0] & 0x40 != 0
+    }
+
+    /// IP bit, the following FUP carries the IP of the instruction that fired the trigger
+    pub const fn ip(&self) -> bool {
+        self.raw[0] & 0x80 != 0
+    }
+
+    /// Instruction count (ICNT) field
+    pub const fn instruction_count(&self) -> u8 {
+        self.raw[1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields() {
+        let trig = Trig { raw: [0xc5, 0x2a] };
+        assert_eq!(trig.trigger_bits(), 0x05);
+        assert!(trig.multiple());
+        assert!(trig.ip());
+        assert_eq!(trig.instruction_count(), 0x2a);
+
+        let trig = Trig { raw: [0x01, 0x00] };
+        assert!(!trig.multiple());
+        assert!(!trig.ip());
+    }
 }
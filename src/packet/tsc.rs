@@ -15,11 +15,28 @@ impl Tsc {
     pub(super) fn try_from_payload(payload: &[u8]) -> Result<Self, PtPacketParseError> {
         // the try_into cannot fail, therefore the unwrap() can never panic.
         let raw = payload
-            .get(..8)
-            .ok_or(PtPacketParseError::MalformedPacket)?
+            .get(..7)
+            .ok_or(PtPacketParseError::Truncated)?
             .try_into()
             .unwrap();
 
         Ok(Self { raw })
     }
+
+    /// Lower 7 bytes of the Time Stamp Counter value
+    pub const fn tsc(&self) -> u64 {
+        let r = &self.raw;
+        u64::from_le_bytes([r[0], r[1], r[2], r[3], r[4], r[5], r[6], 0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tsc() {
+        let tsc = Tsc::try_from_payload(&[0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22]).unwrap();
+        assert_eq!(tsc.tsc(), 0x0022_3344_5566_7788);
+    }
 }
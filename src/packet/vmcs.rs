@@ -15,17 +15,32 @@ impl SizedPtPacket for Vmcs {
 impl Vmcs {
     pub(crate) const SIZE: usize = 7;
     pub(crate) const B1: u8 = 0xc8;
-    // pub const fn vmcs_pointer(&self) -> u64 {
-    //     let raw = [
-    //         self.raw[0],
-    //         self.raw[1],
-    //         self.raw[2],
-    //         self.raw[3],
-    //         self.raw[4],
-    //         0,
-    //         0,
-    //         0,
-    //     ];
-    //     u64::from_le_bytes(raw) << 12
-    // }
+
+    /// Physical address of the VMCS, the VMCS pointer bits 51:12
+    pub const fn vmcs_pointer(&self) -> u64 {
+        let raw = [
+            self.raw[0],
+            self.raw[1],
+            self.raw[2],
+            self.raw[3],
+            self.raw[4],
+            0,
+            0,
+            0,
+        ];
+        u64::from_le_bytes(raw) << 12
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vmcs_pointer() {
+        let vmcs = Vmcs {
+            raw: [0x34, 0x12, 0x00, 0x00, 0x01],
+        };
+        assert_eq!(vmcs.vmcs_pointer(), 0x1000_0123_4000);
+    }
 }
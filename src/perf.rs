@@ -0,0 +1,86 @@
+use crate::PtDecoderError;
+use std::borrow::Cow;
+
+/// PT trace stored in the AUX area of a Linux `perf_event_open` ring buffer, as recorded by e.g.
+/// `perf record -e intel_pt//`.
+///
+/// - `aux` is the whole AUX area, mapped at `perf_event_mmap_page::aux_offset` with size
+///   `perf_event_mmap_page::aux_size`.
+/// - `aux_tail` and `aux_head` are the `perf_event_mmap_page` fields with the same name. They are
+///   free running offsets, their value modulo `aux.len()` is the position in the buffer.
+///
+/// Returns the bytes between `aux_tail` and `aux_head` in order, reassembled if they wrap around
+/// the end of the buffer. In snapshot mode the kernel overwrites old data and does not update
+/// `aux_tail`, use `aux_head.saturating_sub(aux.len() as u64)` instead. The returned trace
+/// usually does not start with a PSB, the decoders sync on the first one.
+///
+/// Returns [`PtDecoderError::InvalidArgument`] if `aux` is empty, or if `aux_tail` is greater
+/// than `aux_head` or more than `aux.len()` bytes behind it.
+pub fn perf_aux_trace(
+    aux: &[u8],
+    aux_tail: u64,
+    aux_head: u64,
+) -> Result<Cow<'_, [u8]>, PtDecoderError> {
+    let len = aux_head
+        .checked_sub(aux_tail)
+        .and_then(|len| usize::try_from(len).ok())
+        .filter(|&len| len <= aux.len() && !aux.is_empty())
+        .ok_or(PtDecoderError::InvalidArgument)?;
+    // Lossless, aux.len() fits in a u64
+    let start = (aux_tail % aux.len() as u64) as usize;
+
+    match aux[start..].get(..len) {
+        Some(trace) => Ok(Cow::Borrowed(trace)),
+        None => {
+            let mut trace = aux[start..].to_vec();
+            trace.extend_from_slice(&aux[..len - trace.len()]);
+            Ok(Cow::Owned(trace))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contiguous() {
+        let aux = [0, 1, 2, 3, 4, 5, 6, 7];
+        let trace = perf_aux_trace(&aux, 0x102, 0x106).unwrap();
+        assert!(matches!(trace, Cow::Borrowed(_)));
+        assert_eq!(*trace, [2, 3, 4, 5]);
+
+        assert_eq!(*perf_aux_trace(&aux, 8, 16).unwrap(), aux);
+        assert_eq!(*perf_aux_trace(&aux, 5, 5).unwrap(), []);
+    }
+
+    #[test]
+    fn wrapped() {
+        let aux = [4, 5, 6, 7, 0, 1, 2, 3];
+        let trace = perf_aux_trace(&aux, 0x14, 0x1a).unwrap();
+        assert!(matches!(trace, Cow::Owned(_)));
+        assert_eq!(*trace, [0, 1, 2, 3, 4, 5]);
+
+        assert_eq!(
+            *perf_aux_trace(&aux, 4, 12).unwrap(),
+            [0, 1, 2, 3, 4, 5, 6, 7]
+        );
+    }
+
+    #[test]
+    fn invalid_offsets() {
+        let aux = [0; 8];
+        assert_eq!(
+            perf_aux_trace(&aux, 1, 0),
+            Err(PtDecoderError::InvalidArgument)
+        );
+        assert_eq!(
+            perf_aux_trace(&aux, 0, 9),
+            Err(PtDecoderError::InvalidArgument)
+        );
+        assert_eq!(
+            perf_aux_trace(&[], 0, 0),
+            Err(PtDecoderError::InvalidArgument)
+        );
+    }
+}
@@ -0,0 +1,72 @@
+//! Traces and images shared by the decoder tests
+
+use crate::coverage_decoder::{PtCoverageDecoder, PtCoverageDecoderBuilder};
+use crate::image::PtImage;
+use crate::packet::psb::{Psb, PsbEnd};
+
+pub const PSBEND: [u8; PsbEnd::SIZE] = [Psb::B0, PsbEnd::B1];
+pub const MODE_EXEC_64: [u8; 2] = [0x99, 0x01];
+pub const SAMPLE_TIP_CALLEE: [u8; 3] = [0x2d, 0x20, 0x10];
+
+/// Concatenate raw packets into a trace
+pub fn trace(packets: &[&[u8]]) -> Vec<u8> {
+    packets.concat()
+}
+
+/// 64 bit code at 0x1000:
+///
+/// ```text
+/// 0x1000: test rax, rax
+/// 0x1003: jne 0x1009
+/// 0x1005: call rbx
+/// 0x1007: jmp 0x1000
+/// 0x1009: ret
+/// ...
+/// 0x1020: ret
+/// ```
+pub fn sample_image() -> PtImage {
+    let mut data = vec![0x90; 0x30];
+    data[..0xa].copy_from_slice(&[0x48, 0x85, 0xc0, 0x75, 0x04, 0xff, 0xd3, 0xeb, 0xf7, 0xc3]);
+    data[0x20] = 0xc3;
+    PtImage::new(data, 0x1000)
+}
+
+/// Trace of `sample_image` executing: jne not taken, call rbx to 0x1020, return to 0x1007,
+/// jmp 0x1000, jne taken, ret with tracing disabled.
+pub fn sample_trace() -> Vec<u8> {
+    trace(&[
+        &Psb::CONTENT,
+        &MODE_EXEC_64,
+        &PSBEND,
+        &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+        &[0x04],             // TNT N
+        &SAMPLE_TIP_CALLEE,  // TIP 0x1020
+        &[0x2d, 0x07, 0x10], // TIP 0x1007
+        &[0x06],             // TNT T
+        &[0x01],             // TIP.PGD, IP suppressed
+    ])
+}
+
+/// Trace of `sample_image` starting a TSX transaction, then calling 0x1020 inside it
+pub fn transaction_trace() -> Vec<u8> {
+    trace(&[
+        &Psb::CONTENT,
+        &MODE_EXEC_64,
+        &PSBEND,
+        &[0x31, 0x00, 0x10], // TIP.PGE 0x1000
+        &[0x99, 0x21],       // MODE.TSX Begin
+        &[0x3d, 0x00, 0x10], // FUP 0x1000
+        &[0x04],             // TNT N
+        &SAMPLE_TIP_CALLEE,  // TIP 0x1020
+    ])
+}
+
+/// Builder of a decoder of `sample_image`
+pub fn sample_builder() -> PtCoverageDecoderBuilder {
+    PtCoverageDecoderBuilder::new().images(vec![sample_image()])
+}
+
+/// Decoder of `sample_image` with the default options
+pub fn sample_decoder() -> PtCoverageDecoder {
+    sample_builder().build().unwrap()
+}
@@ -3,6 +3,12 @@ pub const fn sign_extend_48(x: u64) -> u64 {
     ((x << 16) as i64 >> 16) as u64
 }
 
+/// Returns true if `x` is a canonical 48-bit linear address, i.e. bits 48 to 63 are all equal to
+/// bit 47.
+pub const fn is_canonical_48(x: u64) -> bool {
+    sign_extend_48(x) == x
+}
+
 /// Murmur3 finalizer (mixer)
 ///
 /// Bit shuffler for u64 that has a good avalanche effect.
@@ -30,4 +36,13 @@ mod tests {
         let result = sign_extend_48(negative);
         assert_eq!(result, 0xffff << 48 | negative);
     }
+
+    #[test]
+    fn is_canonical_48_works() {
+        assert!(is_canonical_48(0));
+        assert!(is_canonical_48(0x7fff_ffff_ffff));
+        assert!(is_canonical_48(0xffff_8000_0000_0000));
+        assert!(!is_canonical_48(0x8000_0000_0000));
+        assert!(!is_canonical_48(0x0001_0000_0000_0000));
+    }
 }